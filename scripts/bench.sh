@@ -0,0 +1,47 @@
+#!/bin/sh
+# Throughput of the legacy sender and receiver over a veth pair, for each backend.
+#
+#   sudo scripts/bench.sh [file size in bytes] [backends]
+#
+# Builds the release binary, creates the pair bench0/bench1 with a 9000-byte MTU
+# and sends the 1000 files the legacy roles expect in ./data, 1.5 MB each unless
+# told otherwise. Prints the rate seen by the receiver for each backend.
+set -e
+
+size=${1:-1500000}
+backends=${2:-"pnet ring"}
+files=1000
+
+cargo build --release
+robust=$(pwd)/target/release/robust
+work=$(mktemp -d)
+trap 'ip link del bench0 2>/dev/null; rm -rf "$work"' EXIT
+
+ip link add bench0 type veth peer name bench1
+ip link set bench0 mtu 9000 up
+ip link set bench1 mtu 9000 up
+sender=$(cat /sys/class/net/bench0/address)
+receiver=$(cat /sys/class/net/bench1/address)
+
+mkdir -p "$work/send/data" "$work/recv"
+i=0
+while [ $i -lt $files ]; do
+    head -c "$size" /dev/urandom > "$work/send/data/data$i"
+    i=$((i + 1))
+done
+
+for backend in $backends; do
+    rm -rf "$work/recv/data" && mkdir "$work/recv/data"
+    (cd "$work/recv" && exec "$robust" - bench1 receiver "$backend" "$sender" 2>/dev/null) &
+    recv=$!
+    sleep 1
+    start=$(date +%s.%N)
+    (cd "$work/send" && exec "$robust" auto bench0 sender "$backend" "$receiver" 2>/dev/null) &
+    send=$!
+    wait $recv
+    end=$(date +%s.%N)
+    kill $send
+    wait $send 2>/dev/null || true
+    awk -v b="$backend" -v n=$files -v s="$size" -v t0="$start" -v t1="$end" \
+        'BEGIN { printf "%s: %d files of %d bytes in %.2f s, %.1f MB/s\n", b, n, s, t1 - t0, n * s / (t1 - t0) / 1e6 }'
+done
@@ -0,0 +1,229 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+    time,
+};
+
+use pnet::util::MacAddr;
+
+use crate::{eft, general};
+use super::inotify;
+
+// Files dropped into the watched directory are sent to the peer and then moved to
+// sent/, or to failed/ when the transfer fails. A file is picked up once it was
+// closed after writing (or moved in) and has not changed for SETTLE_INTERVAL.
+// Dot files and subdirectories are left alone.
+//
+// Each delivered file is written to sent/.delivered before it is moved, so that a
+// restart in between moves it without sending it again. Fileids cycle through
+// 0..DAEMON_FILEIDS, one per file of a batch.
+
+const SENT: &str = "sent";
+const FAILED: &str = "failed";
+const LEDGER: &str = ".delivered";
+
+// size, mtime seconds and nanoseconds, which tell a file from a later one of the same name
+type Version = (u64, i64, i64);
+
+struct Pending {
+    version: Version,
+    since: time::Instant, // last seen changing
+}
+
+pub struct HotFolder {
+    dir: PathBuf,
+    peer: MacAddr,
+    mtu: usize,
+    options: eft::SendOptions,
+    pending: HashMap<OsString, Pending>,
+    fileid: u16,
+}
+
+impl HotFolder {
+    pub fn new(dir: &str, peer: MacAddr, mtu: usize, options: &eft::SendOptions) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            peer: peer,
+            mtu: mtu,
+            options: options.clone(),
+            pending: HashMap::new(),
+            fileid: 0,
+        }
+    }
+
+    // watches the directory until an error occurs
+    pub fn run(&mut self, interface: &mut eft::InterfaceSendMode) -> io::Result<()> {
+        fs::create_dir_all(self.dir.join(SENT))?;
+        fs::create_dir_all(self.dir.join(FAILED))?;
+        // files written before the watch are found by the scan
+        let watch = inotify::Watch::new(&self.dir, libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MODIFY)?;
+        self.recover()?;
+        self.scan()?;
+        let settle = time::Duration::from_millis(general::SETTLE_INTERVAL);
+        loop {
+            for (mask, name) in watch.wait(settle)? {
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    self.scan()?;
+                } else if mask & libc::IN_MODIFY != 0 {
+                    // still being written, its close brings it back
+                    self.pending.remove(&name);
+                } else {
+                    self.touch(name);
+                }
+            }
+            let ready = self.ready();
+            if !ready.is_empty() {
+                self.send(interface, ready)?;
+            }
+        }
+    }
+
+    // moves the files a previous run delivered but did not move
+    fn recover(&self) -> io::Result<()> {
+        let ledger = self.dir.join(SENT).join(LEDGER);
+        for (name, version) in read_ledger(&ledger)? {
+            let path = self.dir.join(&name);
+            if version_of(&path).ok() == Some(version) {
+                self.move_to(&name, SENT)?;
+            }
+        }
+        clear_ledger(&ledger)
+    }
+
+    fn scan(&mut self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            self.touch(entry?.file_name());
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, name: OsString) {
+        if name.as_bytes().first().map_or(true, |b| *b == b'.') {
+            return;
+        }
+        if let Ok(version) = version_of(&self.dir.join(&name)) {
+            self.pending.insert(name, Pending {
+                version: version,
+                since: time::Instant::now(),
+            });
+        }
+    }
+
+    // settled files, as many as there are fileids
+    fn ready(&mut self) -> Vec<(OsString, Version)> {
+        let mut ready: Vec<(OsString, Version)> = Vec::new();
+        let mut gone: Vec<OsString> = Vec::new();
+        for (name, p) in self.pending.iter_mut() {
+            match version_of(&self.dir.join(name)) {
+                Ok(version) if version != p.version => {
+                    p.version = version;
+                    p.since = time::Instant::now();
+                },
+                Ok(_) => {
+                    if p.since.elapsed().as_millis() >= general::SETTLE_INTERVAL as u128 && ready.len() < general::DAEMON_FILEIDS as usize {
+                        ready.push((name.clone(), p.version));
+                    }
+                },
+                Err(_) => gone.push(name.clone()),
+            }
+        }
+        for name in gone.iter().chain(ready.iter().map(|(name, _)| name)) {
+            self.pending.remove(name);
+        }
+        ready
+    }
+
+    fn send(&mut self, interface: &mut eft::InterfaceSendMode, files: Vec<(OsString, Version)>) -> io::Result<()> {
+        let limit = interface.max_size(self.mtu, &self.options)?;
+        let mut handles: Vec<(OsString, Version, io::Result<eft::SendHandle>)> = Vec::new();
+        for (name, version) in files {
+            // compressed files are checked against it once compressed, by the interface
+            if self.options.compression.is_none() && version.0 > limit {
+                eprintln!("failed {}: {} bytes, more than the {} a transfer carries at mtu {}", name.to_string_lossy(), version.0, limit, self.mtu);
+                self.move_to(&name, FAILED)?;
+                continue;
+            }
+            let filepath = self.dir.join(&name).to_string_lossy().into_owned();
+            let handle = interface.send_with(self.fileid, self.peer, filepath, self.mtu, &self.options);
+            self.fileid = (self.fileid + 1) % general::DAEMON_FILEIDS;
+            handles.push((name, version, handle));
+        }
+
+        let ledger = self.dir.join(SENT).join(LEDGER);
+        for (name, version, handle) in handles {
+            match handle.and_then(|handle| handle.wait()) {
+                Ok(()) => {
+                    // on record before anything else can fail, or a restart would send it again
+                    append_ledger(&ledger, &name, version)?;
+                    println!("sent {}", name.to_string_lossy());
+                    self.move_to(&name, SENT)?;
+                },
+                Err(e) => {
+                    eprintln!("failed {}: {}", name.to_string_lossy(), e);
+                    self.move_to(&name, FAILED)?;
+                },
+            }
+        }
+        clear_ledger(&ledger)
+    }
+
+    // a file removed meanwhile is not an error
+    fn move_to(&self, name: &OsString, subdir: &str) -> io::Result<()> {
+        match fs::rename(self.dir.join(name), self.dir.join(subdir).join(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => r,
+        }
+    }
+}
+
+fn version_of(path: &Path) -> io::Result<Version> {
+    let m = fs::metadata(path)?;
+    if !m.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"));
+    }
+    Ok((m.len(), m.mtime(), m.mtime_nsec()))
+}
+
+// one `size mtime mtime_nsec name` line per file
+fn read_ledger(path: &Path) -> io::Result<HashSet<(OsString, Version)>> {
+    let mut raw: Vec<u8> = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut raw)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+    // a line cut short by a crash while appending is left out
+    let complete = raw.len() - raw.iter().rev().take_while(|b| **b != b'\n').count();
+    let mut entries: HashSet<(OsString, Version)> = HashSet::new();
+    for line in raw[..complete].split(|b| *b == b'\n') {
+        let fields: Vec<&[u8]> = line.splitn(4, |b| *b == b' ').collect();
+        if fields.len() != 4 {
+            continue;
+        }
+        let number = |field: &[u8]| String::from_utf8_lossy(field).parse::<i64>().ok();
+        if let (Some(size), Some(secs), Some(nsecs)) = (number(fields[0]), number(fields[1]), number(fields[2])) {
+            entries.insert((OsString::from_vec(fields[3].to_vec()), (size as u64, secs, nsecs)));
+        }
+    }
+    Ok(entries)
+}
+
+fn clear_ledger(path: &Path) -> io::Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)?.sync_all()
+}
+
+fn append_ledger(path: &Path, name: &OsString, (size, secs, nsecs): Version) -> io::Result<()> {
+    let mut raw: Vec<u8> = format!("{} {} {} ", size, secs, nsecs).into_bytes();
+    raw.extend_from_slice(name.as_bytes());
+    raw.push(b'\n');
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(&raw)?;
+    file.sync_all()
+}
@@ -0,0 +1,254 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time,
+};
+
+use pnet::util::MacAddr;
+
+use crate::{eft, general};
+
+// Receives what a peer's hot folder sends, on every fileid in 0..DAEMON_FILEIDS.
+// A file is written to a temporary file next to its destination and synced; once
+// the size and digest carried by Meta check out it is renamed into place, so the
+// output directory never holds partial files.
+//
+// The destination is the template below the output directory, with {name} (the
+// sender's path), {stem}, {ext}, {peer} and {date} (UTC, yyyy-mm-dd) filled in.
+// Temporary files left behind by a crash are removed when the inbox starts.
+
+const PART: &str = "part"; // extension of temporary files, which start with a dot
+
+// what to do when the destination already exists
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Collision {
+    Overwrite,
+    Skip,
+    Suffix, // name-1.ext, name-2.ext, ...
+}
+
+impl Default for Collision {
+    fn default() -> Self {
+        Collision::Suffix
+    }
+}
+
+#[derive(Clone)]
+pub struct Inbox {
+    dir: PathBuf,
+    peer: MacAddr,
+    template: String,
+    collision: Collision,
+}
+
+impl Inbox {
+    pub fn new(dir: &str, peer: MacAddr, template: &str, collision: Collision) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            peer: peer,
+            template: template.to_string(),
+            collision: collision,
+        }
+    }
+
+    // receives until any fileid cannot be listened on or the interface stops, and
+    // returns that error
+    pub fn run(&self, interface: &eft::InterfaceRecvMode) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        clean(&self.dir)?;
+        let (failed_tx, failed_rx) = mpsc::channel();
+        for fileid in 0..general::DAEMON_FILEIDS {
+            let mut interface = interface.clone();
+            let inbox = self.clone();
+            let failed = failed_tx.clone();
+            thread::spawn(move || loop {
+                let mut stream = match interface.stream(fileid, inbox.peer) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        failed.send((fileid, e)).ok();
+                        return;
+                    },
+                };
+                match inbox.receive(&mut stream, fileid) {
+                    Ok(Some(path)) => println!("received {}", path.display()),
+                    Ok(None) => (),
+                    Err(e) if eft::is_fatal(&e) => {
+                        failed.send((fileid, e)).ok();
+                        return;
+                    },
+                    Err(e) => eprintln!("failed fileid {}: {}", fileid, e),
+                }
+            });
+        }
+        drop(failed_tx);
+        match failed_rx.recv() {
+            Ok((fileid, e)) => Err(io::Error::new(e.kind(), format!("fileid {}: {}", fileid, e))),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "receiving threads panicked")),
+        }
+    }
+
+    // the path the file was placed at, None if it was skipped
+    fn receive(&self, stream: &mut eft::RecvStream, fileid: u16) -> io::Result<Option<PathBuf>> {
+        let data = stream.read()?;
+        let metadata = stream.metadata().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "metadata was not received")
+        })?;
+        let target = eft::meta::target_path(&self.dir, &self.expand(&metadata.path))?;
+        let parent = target.parent().unwrap_or(&self.dir);
+        fs::create_dir_all(parent)?;
+
+        let part = parent.join(format!(".{}.{}.{}", file_name(&target), fileid, PART));
+        let written = File::create(&part)
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.sync_all()
+            })
+            .and_then(|_| metadata.apply(&part))
+            .and_then(|_| self.place(&part, &target));
+        if written.is_err() {
+            fs::remove_file(&part).ok();
+        }
+        let placed = written?;
+        File::open(parent)?.sync_all()?;
+        Ok(placed)
+    }
+
+    // moves `part` to `target` or next to it, following the collision policy
+    fn place(&self, part: &Path, target: &Path) -> io::Result<Option<PathBuf>> {
+        if self.collision == Collision::Overwrite {
+            fs::rename(part, target)?;
+            return Ok(Some(target.to_path_buf()));
+        }
+        // a hard link fails rather than replace an existing file
+        let mut n = 0;
+        loop {
+            let candidate = if n == 0 { target.to_path_buf() } else { suffixed(target, n) };
+            match fs::hard_link(part, &candidate) {
+                Ok(()) => {
+                    fs::remove_file(part)?;
+                    return Ok(Some(candidate));
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && self.collision == Collision::Skip => {
+                    fs::remove_file(part)?;
+                    return Ok(None);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // in a single pass, placeholders in the sender's name are not expanded
+    fn expand(&self, name: &str) -> String {
+        let path = Path::new(name);
+        let mut expanded = String::new();
+        let mut rest = &*self.template;
+        while let Some(start) = rest.find('{') {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            match &rest[..=end] {
+                "{name}" => expanded.push_str(name),
+                "{stem}" => expanded.push_str(&path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned())),
+                "{ext}" => expanded.push_str(&path.extension().map_or(String::new(), |e| e.to_string_lossy().into_owned())),
+                "{peer}" => expanded.push_str(&self.peer.to_string().replace(':', "-")),
+                "{date}" => expanded.push_str(&utc_date(time::SystemTime::now())),
+                _ => {
+                    expanded.push('{');
+                    rest = &rest[1..];
+                    continue;
+                },
+            }
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+// removes the temporary files under `dir`, without following symlinks
+fn clean(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            clean(&entry.path())?;
+        } else if file_type.is_file() && is_part(&entry.file_name().to_string_lossy()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// .name.fileid.part
+fn is_part(name: &str) -> bool {
+    name.strip_prefix('.')
+        .and_then(|name| name.strip_suffix(PART))
+        .and_then(|name| name.strip_suffix('.'))
+        .and_then(|name| name.rsplit_once('.'))
+        .map_or(false, |(name, fileid)| !name.is_empty() && fileid.parse::<u16>().is_ok())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned())
+}
+
+// name-n.ext
+fn suffixed(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+// yyyy-mm-dd, from the days since the epoch (Howard Hinnant's civil_from_days)
+fn utc_date(t: time::SystemTime) -> String {
+    let days = t.duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inbox(template: &str) -> Inbox {
+        Inbox::new("/tmp", MacAddr::new(2, 0, 0, 0, 0, 1), template, Collision::Suffix)
+    }
+
+    #[test]
+    fn expand_once() {
+        assert_eq!(inbox("{peer}/{stem}-x.{ext}").expand("a/b.txt"), "02-00-00-00-00-01/b-x.txt");
+        assert_eq!(inbox("in/{name}").expand("{stem}{peer}.{ext}"), "in/{stem}{peer}.{ext}");
+        assert_eq!(inbox("{{name}}{nope}{").expand("a"), "{a}{nope}{");
+    }
+
+    #[test]
+    fn clean_parts() {
+        let dir = std::env::temp_dir().join(format!("robust-inbox-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in [".a.txt.3.part", "sub/.b.0.part", "a.txt", ".a.part", ".x.y.part", "sub/c"].iter() {
+            File::create(dir.join(name)).unwrap();
+        }
+        clean(&dir).unwrap();
+        for (name, kept) in [(".a.txt.3.part", false), ("sub/.b.0.part", false), ("a.txt", true), (".a.part", true), (".x.y.part", true), ("sub/c", true)].iter() {
+            assert_eq!(dir.join(name).exists(), *kept, "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    ffi::{CString, OsStr, OsString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    time,
+};
+
+// struct inotify_event without its name: wd, mask, cookie and len
+const EVENT_HEADER_LENGTH: usize = 16;
+
+// room for several events with names up to NAME_MAX
+const EVENT_BUFFER_LENGTH: usize = 4096;
+
+// an inotify instance watching one directory
+pub struct Watch {
+    fd: libc::c_int,
+}
+
+impl Watch {
+    pub fn new(dir: &Path, mask: u32) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let watch = Self { fd: fd };
+        let path = CString::new(dir.as_os_str().as_bytes()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid path")
+        })?;
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(watch)
+    }
+
+    // the events as (mask, name) that arrive within `timeout`
+    pub fn wait(&self, timeout: time::Duration) -> io::Result<Vec<(u32, OsString)>> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+
+        let mut events: Vec<(u32, OsString)> = Vec::new();
+        let mut buffer = [0u8; EVENT_BUFFER_LENGTH];
+        loop {
+            let n = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    break;
+                }
+                return Err(e);
+            }
+            let mut pos = 0;
+            while pos + EVENT_HEADER_LENGTH <= n as usize {
+                let mask = u32::from_ne_bytes([buffer[pos + 4], buffer[pos + 5], buffer[pos + 6], buffer[pos + 7]]);
+                let length = u32::from_ne_bytes([buffer[pos + 12], buffer[pos + 13], buffer[pos + 14], buffer[pos + 15]]) as usize;
+                // the name is padded with NULs
+                let name = &buffer[pos + EVENT_HEADER_LENGTH..pos + EVENT_HEADER_LENGTH + length];
+                let name = name.split(|b| *b == 0).next().unwrap_or(&[]);
+                events.push((mask, OsStr::from_bytes(name).to_os_string()));
+                pos += EVENT_HEADER_LENGTH + length;
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
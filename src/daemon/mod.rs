@@ -0,0 +1,8 @@
+// Long running modes of the robust binary.
+
+mod hotfolder;
+mod inbox;
+mod inotify;
+
+pub use self::hotfolder::HotFolder;
+pub use self::inbox::{Collision, Inbox};
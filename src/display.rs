@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    io::{
+        self,
+        IsTerminal,
+        Write,
+    },
+    sync::mpsc,
+    time,
+};
+
+use pnet::util::MacAddr;
+
+use crate::eft::{
+    Progress, Status,
+};
+use crate::general;
+
+// Renders the progress reports of the robust binary on stderr. A terminal gets a bar
+// for each running file and a total line, redrawn in place; anything else a line
+// per finished file.
+
+const BARS: usize = 8; // running files shown at once
+const BAR_WIDTH: usize = 30;
+const NAME_WIDTH: usize = 24;
+
+pub struct Display {
+    rx: mpsc::Receiver<Progress>,
+    expected: usize, // files to wait for
+    tty: bool,
+    running: HashMap<(MacAddr, u16), Progress>,
+    done: usize,
+    failed: usize,
+    bytes: u64, // of finished files
+    started: time::Instant,
+    lines: usize, // drawn last time
+}
+
+impl Display {
+    pub fn new(rx: mpsc::Receiver<Progress>, expected: usize) -> Self {
+        Self {
+            rx: rx,
+            expected: expected,
+            tty: io::stderr().is_terminal(),
+            running: HashMap::new(),
+            done: 0,
+            failed: 0,
+            bytes: 0,
+            started: time::Instant::now(),
+            lines: 0,
+        }
+    }
+
+    // returns once `expected` files finished or the interface is gone
+    pub fn run(mut self) {
+        let interval = time::Duration::from_millis(general::PROGRESS_INTERVAL);
+        let mut drawn = time::Instant::now();
+        while self.done + self.failed < self.expected {
+            match self.rx.recv_timeout(interval) {
+                Ok(progress) => self.update(progress),
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            if self.tty && drawn.elapsed() >= interval {
+                self.draw();
+                drawn = time::Instant::now();
+            }
+        }
+        if self.tty {
+            self.draw();
+        }
+    }
+
+    fn update(&mut self, progress: Progress) {
+        let key = (progress.peer, progress.fileid);
+        match progress.status {
+            Status::Running => {
+                self.running.insert(key, progress);
+                return;
+            },
+            Status::Done => self.done += 1,
+            Status::Failed => self.failed += 1,
+        }
+        self.running.remove(&key);
+        self.bytes += progress.bytes;
+        if !self.tty {
+            let status = if progress.status == Status::Done { "done" } else { "failed" };
+            eprintln!("{} {} {} in {:.1}s, {}/s", label(&progress), status,
+                bytes(progress.bytes), progress.elapsed.as_secs_f64(), bytes(progress.rate() as u64));
+        }
+    }
+
+    fn draw(&mut self) {
+        let mut out = String::new();
+        if self.lines > 0 {
+            out.push_str(&format!("\x1b[{}A", self.lines));
+        }
+        out.push_str("\x1b[J");
+        let mut running: Vec<&Progress> = self.running.values().collect();
+        running.sort_by_key(|p| (p.peer.to_string(), p.fileid));
+        for p in running.iter().take(BARS) {
+            let eta = p.eta().map_or(String::from("-"), duration);
+            out.push_str(&format!("{:<w$.w$} {} {:>9} {:>9}/s eta {}\n", label(p), bar(p), bytes(p.bytes),
+                bytes(p.rate() as u64), eta, w = NAME_WIDTH));
+        }
+        let hidden = running.len().saturating_sub(BARS);
+        if hidden > 0 {
+            out.push_str(&format!("... {} more\n", hidden));
+        }
+
+        // the total counts running files as far as they got
+        let bytes_now = self.bytes + self.running.values().map(|p| p.bytes).sum::<u64>();
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { bytes_now as f64 / elapsed } else { 0.0 };
+        let finished = self.done + self.failed;
+        let eta = if rate > 0.0 && finished > 0 && finished < self.expected {
+            // assumes the remaining files are as large as the finished ones on average
+            let remaining = (self.bytes / finished as u64) * (self.expected - finished) as u64;
+            duration(time::Duration::from_secs_f64(remaining.saturating_sub(bytes_now - self.bytes) as f64 / rate))
+        } else {
+            String::from("-")
+        };
+        out.push_str(&format!("total {}/{} files, {} failed, {} at {}/s eta {}\n", self.done, self.expected,
+            self.failed, bytes(bytes_now), bytes(rate as u64), eta));
+
+        self.lines = running.len().min(BARS) + (hidden > 0) as usize + 1;
+        let mut stderr = io::stderr();
+        stderr.write_all(out.as_bytes()).ok();
+        stderr.flush().ok();
+    }
+}
+
+fn label(p: &Progress) -> String {
+    if p.name.is_empty() {
+        format!("#{}", p.fileid)
+    } else {
+        p.name.clone()
+    }
+}
+
+fn bar(p: &Progress) -> String {
+    let filled = match p.total_fragments {
+        Some(total) if total > 0 => (p.fragments * BAR_WIDTH / total).min(BAR_WIDTH),
+        _ => 0,
+    };
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+fn bytes(n: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, units[0])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+fn duration(d: time::Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
@@ -0,0 +1,154 @@
+use std::io;
+
+// Compression is applied to the whole file before fragmentation and signalled
+// in the Meta packet; the codecs themselves need the `compression` feature.
+
+// bytes compressed to decide whether compressing the whole file is worth it
+const SAMPLE_LENGTH: usize = 64 * 1024;
+
+// the sample must shrink below this ratio (percent)
+const MAX_SAMPLE_RATIO: usize = 90;
+
+// lz4 cannot expand a byte to more than this, see Compression::decompress
+#[cfg(feature = "compression")]
+const LZ4_MAX_RATIO: usize = 255;
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Codec {
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i8, // ignored by lz4
+}
+
+impl Compression {
+    pub fn from_raw(codec: u8, level: u8) -> io::Result<Option<Self>> {
+        let codec = match codec {
+            0 => return Ok(None),
+            1 => Codec::Zstd,
+            2 => Codec::Lz4,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown codec")),
+        };
+        Ok(Some(Self {
+            codec: codec,
+            level: level as i8,
+        }))
+    }
+
+    // (codec, level) on the wire, (0, 0) for uncompressed
+    pub fn raw(compression: &Option<Self>) -> [u8; 2] {
+        match compression {
+            Some(c) => [c.codec as u8, c.level as u8],
+            None => [0, 0],
+        }
+    }
+
+    // already compressed inputs (archives, media, ...) are sent as they are
+    pub fn worthwhile(&self, data: &[u8]) -> io::Result<bool> {
+        if data.is_empty() {
+            return Ok(false);
+        }
+        let sample = &data[..data.len().min(SAMPLE_LENGTH)];
+        Ok(self.compress(sample)?.len() * 100 < sample.len() * MAX_SAMPLE_RATIO)
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::Zstd => zstd::bulk::compress(data, self.level as i32),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    // `size` is the length announced in Meta. the output never grows past it, whatever
+    // the sender claims inside the compressed data
+    #[cfg(feature = "compression")]
+    pub fn decompress(&self, data: &[u8], size: u64) -> io::Result<Vec<u8>> {
+        use std::{convert::TryFrom, io::Read};
+
+        let size = usize::try_from(size).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "size mismatch"))?;
+        let raw = match self.codec {
+            Codec::Zstd => {
+                // one byte more than allowed tells a longer output apart
+                let mut raw: Vec<u8> = Vec::new();
+                zstd::stream::read::Decoder::new(data)?.take((size as u64).saturating_add(1)).read_to_end(&mut raw)?;
+                raw
+            },
+            Codec::Lz4 => {
+                if data.len() < 4 || u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as u64 != size as u64 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "size mismatch"));
+                }
+                // the output is allocated up front
+                if size > (data.len() - 4).saturating_mul(LZ4_MAX_RATIO) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "size mismatch"));
+                }
+                lz4_flex::decompress(&data[4..], size).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("lz4 error: {}", e))
+                })?
+            },
+        };
+        if raw.len() != size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "size mismatch"));
+        }
+        Ok(raw)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn compress(&self, _data: &[u8]) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::Other, "built without the compression feature"))
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn decompress(&self, _data: &[u8], _size: u64) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::Other, "built without the compression feature"))
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    const CODECS: [Codec; 2] = [Codec::Zstd, Codec::Lz4];
+
+    fn compression(codec: Codec) -> Compression {
+        Compression { codec: codec, level: 3 }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = vec![7; 100_000];
+        for codec in CODECS.iter() {
+            let c = compression(*codec);
+            assert_eq!(c.decompress(&c.compress(&data).unwrap(), data.len() as u64).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn output_capped() {
+        // a bomb: far more output than the Meta size announces
+        let data = vec![0; 10_000_000];
+        for codec in CODECS.iter() {
+            let c = compression(*codec);
+            let compressed = c.compress(&data).unwrap();
+            for size in [0, 1000, data.len() as u64 - 1, data.len() as u64 + 1, u64::MAX].iter() {
+                let e = c.decompress(&compressed, *size).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
+    #[test]
+    fn lz4_prefix() {
+        let c = compression(Codec::Lz4);
+        let mut compressed = c.compress(&[1, 2, 3]).unwrap();
+        assert!(c.decompress(&compressed[..3], 3).is_err());
+        // the prefix claims more than the data can hold
+        compressed[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(c.decompress(&compressed, u32::MAX as u64).is_err());
+    }
+}
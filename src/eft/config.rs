@@ -0,0 +1,391 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io,
+    path::Path,
+};
+
+use pnet::util::MacAddr;
+use serde::Deserialize;
+
+use crate::general;
+use super::{
+    crypto, framing, BindOptions, Backend, RateLimit,
+};
+
+// Settings of the robust binary and of Interface::builder, from a TOML file:
+//
+//   interface = "eth0"
+//   backend = "ring"              # or "pnet"
+//   ethertype = 0x0ef7
+//   vlan = { vid = 100, pcp = 0 }
+//   crypto = { cipher = "chacha20poly1305", key_file = "/etc/robust/psk" }
+//   rate_limit = { rate = 100000000, burst = 65536 }
+//
+//   [transfer]
+//   rto = 5
+//   max_fragments = 200
+//
+//   [[peers]]
+//   name = "ingest"
+//   mac = "02:00:00:00:00:01"
+//   rate_limit = { rate = 10000000, burst = 16384 }
+//
+// Every key but peers can be overridden from the environment, ROBUST_ followed by
+// the key in upper case with sections separated by a double underscore
+// (ROBUST_TRANSFER__RTO), and then by `key=value` arguments with dotted keys
+// (transfer.rto=10). Other ROBUST_ variables, such as ROBUST_LOG, are left alone.
+
+const ENV_PREFIX: &str = "ROBUST_";
+
+// the keys the environment can set
+const ENV_KEYS: &[&str] = &[
+    "interface", "backend", "ethertype",
+    "vlan.vid", "vlan.pcp",
+    "crypto.cipher", "crypto.key", "crypto.key_file",
+    "rate_limit.rate", "rate_limit.burst",
+    "transfer.rto", "transfer.bulk_rto", "transfer.burst", "transfer.max_fragments", "transfer.backdate",
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub interface: Option<String>,
+    pub backend: String,
+    pub ethertype: u16,
+    pub vlan: Option<VlanConfig>,
+    pub crypto: Option<CryptoSection>,
+    pub rate_limit: Option<RateLimitConfig>, // the interface as a whole
+    pub transfer: Tuning,
+    pub peers: Vec<PeerConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VlanConfig {
+    pub vid: u16,
+    #[serde(default)]
+    pub pcp: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CryptoSection {
+    pub cipher: String,
+    pub key: Option<String>, // hex
+    pub key_file: Option<String>, // raw bytes
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub rate: u64, // bytes per second
+    pub burst: usize, // bytes
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub name: String,
+    pub mac: String,
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+// knobs of the send path
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tuning {
+    pub rto: u32, // milliseconds, files sent one at a time with send and send_with
+    pub bulk_rto: u32, // milliseconds, everything else
+    pub burst: usize, // fragments written per pass of the send loop
+    pub max_fragments: usize, // per file, at most MAX_OFFSET_LENGTH
+    pub backdate: u64, // milliseconds new timers start in the past, so that first sends are not delayed
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interface: None,
+            backend: String::from("pnet"),
+            ethertype: general::ETHERTYPE,
+            vlan: None,
+            crypto: None,
+            rate_limit: None,
+            transfer: Tuning::default(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            rto: general::RTO,
+            bulk_rto: general::BULK_RTO,
+            burst: general::SEND_BURST,
+            max_fragments: general::MAX_FRAGMENTS,
+            backdate: general::TIMER_BACKDATE,
+        }
+    }
+}
+
+impl Config {
+    // `path` if any, then the environment, then `overrides` as key=value
+    pub fn load(path: Option<&Path>, overrides: &[String]) -> io::Result<Self> {
+        Self::load_from(path, env::vars_os(), overrides)
+    }
+
+    fn load_from<I: Iterator<Item = (OsString, OsString)>>(path: Option<&Path>, vars: I, overrides: &[String]) -> io::Result<Self> {
+        let mut table = match path {
+            Some(path) => fs::read_to_string(path)?.parse::<toml::Table>().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e.message()))
+            })?,
+            None => toml::Table::new(),
+        };
+        for (name, value) in vars {
+            let key = match name.to_str().and_then(|name| name.strip_prefix(ENV_PREFIX)) {
+                Some(key) => key.to_lowercase().replace("__", "."),
+                None => continue,
+            };
+            if !ENV_KEYS.contains(&&*key) {
+                continue;
+            }
+            let value = value.into_string().map_err(|_| {
+                invalid(format!("{}: not valid unicode", name.to_string_lossy()))
+            })?;
+            set(&mut table, &key, &value)?;
+        }
+        for dotted in overrides {
+            let (key, value) = dotted.split_once('=').ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{}: expected key=value", dotted))
+            })?;
+            set(&mut table, key.trim(), value.trim())?;
+        }
+        let config: Self = toml::Value::Table(table).try_into().map_err(|e: toml::de::Error| {
+            io::Error::new(io::ErrorKind::InvalidData, e.message().to_string())
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        self.transfer.validate()?;
+        self.bind_options()?;
+        self.rate_limits()?;
+        Ok(())
+    }
+
+    pub fn bind_options(&self) -> io::Result<BindOptions> {
+        let backend = match &*self.backend {
+            "pnet" => Backend::Pnet,
+            #[cfg(target_os = "linux")]
+            "ring" => Backend::Ring,
+            _ => return Err(invalid(format!("unknown backend {}", self.backend))),
+        };
+        let vlan = self.vlan.as_ref().map(|v| framing::Vlan { vid: v.vid, pcp: v.pcp });
+        framing::Framing::new(self.ethertype, vlan)?;
+        let crypto = match self.crypto.as_ref() {
+            Some(c) => Some(c.crypto_config()?),
+            None => None,
+        };
+        Ok(BindOptions {
+            crypto: crypto,
+            ethertype: self.ethertype,
+            vlan: vlan,
+            backend: backend,
+            tuning: self.transfer,
+        })
+    }
+
+    // for the interface and per peer
+    pub fn rate_limits(&self) -> io::Result<(Option<RateLimit>, Vec<(MacAddr, RateLimit)>)> {
+        let interface = match self.rate_limit.as_ref() {
+            Some(limit) => Some(RateLimit::new(limit.rate, limit.burst)?),
+            None => None,
+        };
+        let mut peers: Vec<(MacAddr, RateLimit)> = Vec::new();
+        for peer in self.peers.iter() {
+            let mac = peer.mac.parse::<MacAddr>().map_err(|_| {
+                invalid(format!("peer {}: invalid mac address {}", peer.name, peer.mac))
+            })?;
+            if let Some(limit) = peer.rate_limit.as_ref() {
+                peers.push((mac, RateLimit::new(limit.rate, limit.burst)?));
+            }
+        }
+        Ok((interface, peers))
+    }
+
+    // the address of a peer listed by name
+    pub fn peer(&self, name: &str) -> Option<MacAddr> {
+        self.peers.iter()
+            .find(|peer| peer.name == name)
+            .and_then(|peer| peer.mac.parse::<MacAddr>().ok())
+    }
+}
+
+impl CryptoSection {
+    fn crypto_config(&self) -> io::Result<crypto::CryptoConfig> {
+        let cipher = match &*self.cipher {
+            "chacha20poly1305" => crypto::Cipher::ChaCha20Poly1305,
+            "aes256gcm" => crypto::Cipher::Aes256Gcm,
+            _ => return Err(invalid(format!("unknown cipher {}", self.cipher))),
+        };
+        let psk = match (self.key.as_ref(), self.key_file.as_ref()) {
+            (Some(key), None) => from_hex(key).ok_or_else(|| invalid(String::from("crypto.key is not hex")))?,
+            (None, Some(path)) => fs::read(path)?,
+            _ => return Err(invalid(String::from("crypto needs either key or key_file"))),
+        };
+        crypto::CryptoConfig::new(cipher, &psk)
+    }
+}
+
+impl Tuning {
+    pub fn validate(&self) -> io::Result<()> {
+        if self.rto == 0 || self.bulk_rto == 0 {
+            return Err(invalid(String::from("transfer.rto and transfer.bulk_rto must be positive")));
+        }
+        if self.burst == 0 {
+            return Err(invalid(String::from("transfer.burst must be positive")));
+        }
+        if self.max_fragments == 0 || self.max_fragments > general::MAX_OFFSET_LENGTH {
+            return Err(invalid(format!("transfer.max_fragments must be between 1 and {}", general::MAX_OFFSET_LENGTH)));
+        }
+        // otherwise new fragments would wait for their first send
+        if self.backdate <= self.rto.max(self.bulk_rto) as u64 {
+            return Err(invalid(String::from("transfer.backdate must exceed transfer.rto and transfer.bulk_rto")));
+        }
+        Ok(())
+    }
+}
+
+// sets a dotted key, reading the value as TOML and as a plain string otherwise
+fn set(table: &mut toml::Table, key: &str, value: &str) -> io::Result<()> {
+    let value = match format!("v = {}", value).parse::<toml::Table>() {
+        Ok(mut parsed) => parsed.remove("v").unwrap(),
+        Err(_) => toml::Value::String(value.to_string()),
+    };
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().filter(|last| !last.is_empty()).ok_or_else(|| {
+        invalid(format!("invalid key {}", key))
+    })?;
+    let mut table = table;
+    for part in parts {
+        let entry = table.entry(part.to_string()).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = entry.as_table_mut().ok_or_else(|| invalid(format!("{} is not a table", part)))?;
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> std::vec::IntoIter<(OsString, OsString)> {
+        vars.iter().map(|(name, value)| (OsString::from(name), OsString::from(value))).collect::<Vec<_>>().into_iter()
+    }
+
+    fn overrides(overrides: &[&str]) -> Vec<String> {
+        overrides.iter().map(|o| o.to_string()).collect()
+    }
+
+    #[test]
+    fn layers() {
+        let path = env::temp_dir().join(format!("robust-config-{}.toml", std::process::id()));
+        fs::write(&path, "interface = \"eth0\"\n[transfer]\nrto = 7\nburst = 100\n").unwrap();
+        let config = Config::load_from(Some(&path), vars(&[
+            ("ROBUST_TRANSFER__RTO", "8"),
+            ("ROBUST_BACKEND", "ring"),
+        ]), &overrides(&["transfer.rto=9"])).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.interface.as_deref(), Some("eth0"));
+        assert_eq!(config.backend, "ring");
+        assert_eq!(config.transfer.rto, 9);
+        assert_eq!(config.transfer.burst, 100);
+        assert_eq!(config.transfer.bulk_rto, general::BULK_RTO);
+    }
+
+    #[test]
+    fn foreign_variables() {
+        let config = Config::load_from(None, vars(&[
+            ("ROBUST_LOG", "debug"),
+            ("ROBUST_TRANSFER__UNKNOWN", "1"),
+            ("PATH", "/bin"),
+        ]), &[]).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn non_unicode_variables() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let name = OsString::from_vec(b"ROBUST_\xff".to_vec());
+        let value = OsString::from_vec(b"\xff".to_vec());
+        let config = Config::load_from(None, vec![(name, OsString::from("x"))].into_iter(), &[]).unwrap();
+        assert_eq!(config, Config::default());
+        let e = Config::load_from(None, vec![(OsString::from("ROBUST_BACKEND"), value)].into_iter(), &[]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn environment_keys() {
+        // every key the environment may set is one Config knows
+        for key in ENV_KEYS {
+            let mut table = toml::Table::new();
+            set(&mut table, key, "1").unwrap();
+            if let Err(e) = toml::Value::Table(table).try_into::<Config>() {
+                assert!(!e.message().contains("unknown field"), "{}: {}", key, e.message());
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_overrides() {
+        for o in ["transfer.rto", "transfer.=1", "backend.x=1", "unknown=1", "transfer.rto=fast"].iter() {
+            assert!(Config::load_from(None, vars(&[]), &overrides(&[o])).is_err(), "{}", o);
+        }
+    }
+
+    #[test]
+    fn set_values() {
+        let mut table = toml::Table::new();
+        set(&mut table, "transfer.rto", "10").unwrap();
+        set(&mut table, "backend", "ring").unwrap();
+        set(&mut table, "vlan", "{ vid = 3 }").unwrap();
+        assert_eq!(table["transfer"]["rto"].as_integer(), Some(10));
+        assert_eq!(table["backend"].as_str(), Some("ring"));
+        assert_eq!(table["vlan"]["vid"].as_integer(), Some(3));
+        assert!(set(&mut table, "backend.x", "1").is_err());
+        assert!(set(&mut table, "", "1").is_err());
+    }
+
+    #[test]
+    fn tuning_ranges() {
+        assert!(Tuning::default().validate().is_ok());
+        let invalid = [
+            Tuning { rto: 0, ..Tuning::default() },
+            Tuning { bulk_rto: 0, ..Tuning::default() },
+            Tuning { burst: 0, ..Tuning::default() },
+            Tuning { max_fragments: 0, ..Tuning::default() },
+            Tuning { max_fragments: general::MAX_OFFSET_LENGTH + 1, ..Tuning::default() },
+            Tuning { backdate: general::BULK_RTO as u64, ..Tuning::default() },
+        ];
+        for tuning in invalid.iter() {
+            assert_eq!(tuning.validate().unwrap_err().kind(), io::ErrorKind::InvalidInput, "{:?}", tuning);
+        }
+        assert!(Tuning { max_fragments: general::MAX_OFFSET_LENGTH, ..Tuning::default() }.validate().is_ok());
+    }
+}
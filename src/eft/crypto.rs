@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time,
+};
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{
+    aead::{
+        Aead, KeyInit, Payload,
+    },
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use pnet::util::MacAddr;
+use sha2::Sha256;
+
+use super::packet;
+use crate::general;
+
+// Encrypted packet layout (all integers are big endian):
+//
+//   eft header (8) | session (8) | sequence (8) | ciphertext | tag (16)
+//
+// Every transfer has a session chosen by its sender. Each direction derives its
+// key from the pre-shared key, the session, both addresses and the fileid with
+// HKDF-SHA256, the nonce is offset (2) | session (2) | sequence (8), and the header,
+// session and sequence are authenticated as associated data. Sessions and
+// sequences are taken from the clock so that they keep increasing across
+// restarts: packets of an older session, or behind the replay window, are dropped.
+
+const MIN_PSK_LENGTH: usize = 16;
+
+const SECURITY_HEADER_LENGTH: usize = 16;
+
+// sequences accepted out of order
+const REPLAY_WINDOW: u64 = 64;
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Cipher {
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+#[derive(Clone)]
+pub struct CryptoConfig {
+    pub cipher: Cipher,
+    pub psk: Vec<u8>,
+}
+
+impl CryptoConfig {
+    #[allow(dead_code)]
+    pub fn new(cipher: Cipher, psk: &[u8]) -> io::Result<Self> {
+        if psk.len() < MIN_PSK_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "pre-shared key too short"));
+        }
+        Ok(Self {
+            cipher: cipher,
+            psk: psk.to_vec(),
+        })
+    }
+}
+
+struct Outgoing {
+    session: u64,
+    originated: bool, // chosen by us rather than learnt from the peer
+}
+
+#[derive(Default)]
+struct Window {
+    highest: u64,
+    bitmap: u64, // bit n: highest - n was received
+}
+
+impl Window {
+    fn check(&self, seq: u64) -> bool {
+        if seq > self.highest {
+            return true;
+        }
+        let age = self.highest - seq;
+        age < REPLAY_WINDOW && (self.bitmap >> age) & 1 == 0
+    }
+
+    fn update(&mut self, seq: u64) {
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.bitmap = if shift >= REPLAY_WINDOW { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.highest = seq;
+        } else {
+            self.bitmap |= 1 << (self.highest - seq);
+        }
+    }
+}
+
+struct Incoming {
+    session: u64,
+    window: Window,
+}
+
+#[derive(Default)]
+struct State {
+    last: u64,
+    outgoing: HashMap<(MacAddr, u16), Outgoing>, // by (peer or group, fileid)
+    incoming: HashMap<(MacAddr, MacAddr, u16), Incoming>, // by (src, dst, fileid)
+}
+
+impl State {
+    // strictly increasing, and ahead of anything used before a restart
+    fn next(&mut self) -> u64 {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        self.last = now.max(self.last + 1);
+        self.last
+    }
+}
+
+pub struct Keyring {
+    cipher: Cipher,
+    psk: Vec<u8>,
+    state: Mutex<State>,
+}
+
+impl Keyring {
+    pub fn new(config: &CryptoConfig) -> Self {
+        Self {
+            cipher: config.cipher,
+            psk: config.psk.clone(),
+            state: Mutex::default(),
+        }
+    }
+
+    // starts a new session for a transfer we send to `dst`
+    pub fn originate(&self, dst: MacAddr, fileid: u16) {
+        let mut state = self.state.lock().unwrap();
+        let session = state.next();
+        state.outgoing.insert((dst, fileid), Outgoing { session: session, originated: true });
+    }
+
+    // starts a session for the requests of a transfer `dst` will send us, see
+    // InterfaceRecvMode::request. The session of the answer takes its place
+    pub fn solicit(&self, dst: MacAddr, fileid: u16) {
+        let mut state = self.state.lock().unwrap();
+        let session = state.next();
+        state.outgoing.insert((dst, fileid), Outgoing { session: session, originated: false });
+    }
+
+    pub fn seal(&self, src: MacAddr, dst: MacAddr, p: &packet::EftPacket) -> io::Result<packet::EftPacket> {
+        let (session, seq) = {
+            let mut state = self.state.lock().unwrap();
+            let session = state.outgoing.get(&(dst, p.header.id)).map(|o| o.session).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "no session")
+            })?;
+            (session, state.next())
+        };
+
+        let mut header = p.header;
+        header.total_length = (general::EFT_HEADER_LENGTH + general::AEAD_OVERHEAD + p.payload.len()) as u16;
+        let mut payload: Vec<u8> = Vec::with_capacity(general::AEAD_OVERHEAD + p.payload.len());
+        payload.extend_from_slice(&session.to_be_bytes());
+        payload.extend_from_slice(&seq.to_be_bytes());
+        let aad = associated_data(&header, &payload);
+        let key = self.key(session, src, dst, p.header.id);
+        let ciphertext = self.aead(true, &key, &nonce(p.header.offset, session, seq), &aad, &p.payload)?;
+        payload.extend_from_slice(&ciphertext);
+
+        Ok(packet::EftPacket {
+            header: header,
+            payload: payload,
+        })
+    }
+
+    // authenticates and decrypts `p`, failing for forged, tampered, stale or replayed packets
+    pub fn open(&self, src: MacAddr, dst: MacAddr, p: &packet::EftPacket) -> io::Result<packet::EftPacket> {
+        if p.payload.len() < general::AEAD_OVERHEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too short"));
+        }
+        let mut raw = [0; 8];
+        raw.copy_from_slice(&p.payload[0..8]);
+        let session = u64::from_be_bytes(raw);
+        raw.copy_from_slice(&p.payload[8..16]);
+        let seq = u64::from_be_bytes(raw);
+        let fileid = p.header.id;
+
+        let mut state = self.state.lock().unwrap();
+        // replies to our own transfers must belong to their current session
+        let mut floor = state.outgoing.iter()
+            .filter(|((d, f), o)| *f == fileid && o.originated && (*d == src || *d == dst || d.is_multicast()))
+            .map(|(_, o)| o.session)
+            .max()
+            .unwrap_or(0);
+        if let Some(i) = state.incoming.get(&(src, dst, fileid)) {
+            if i.session == session && !i.window.check(seq) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "replayed packet"));
+            }
+            floor = floor.max(i.session);
+        }
+        if session < floor {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stale session"));
+        }
+
+        let aad = associated_data(&p.header, &p.payload[..SECURITY_HEADER_LENGTH]);
+        let key = self.key(session, src, dst, fileid);
+        let plaintext = self.aead(false, &key, &nonce(p.header.offset, session, seq), &aad, &p.payload[SECURITY_HEADER_LENGTH..])?;
+
+        let i = state.incoming.entry((src, dst, fileid)).or_insert(Incoming { session: session, window: Window::default() });
+        if i.session != session {
+            *i = Incoming { session: session, window: Window::default() };
+        }
+        i.window.update(seq);
+        // replies go out in the sender's session, to the sender and to the group
+        let mut peers = vec![src];
+        if dst.is_multicast() {
+            peers.push(dst);
+        }
+        for peer in peers {
+            let o = state.outgoing.entry((peer, fileid)).or_insert(Outgoing { session: session, originated: false });
+            if !o.originated && o.session < session {
+                o.session = session;
+            }
+        }
+
+        let mut header = p.header;
+        header.total_length = (general::EFT_HEADER_LENGTH + plaintext.len()) as u16;
+        Ok(packet::EftPacket {
+            header: header,
+            payload: plaintext,
+        })
+    }
+
+    fn key(&self, session: u64, src: MacAddr, dst: MacAddr, fileid: u16) -> [u8; 32] {
+        let mut info: Vec<u8> = b"eft v1".to_vec();
+        info.extend_from_slice(&[src.0, src.1, src.2, src.3, src.4, src.5]);
+        info.extend_from_slice(&[dst.0, dst.1, dst.2, dst.3, dst.4, dst.5]);
+        info.extend_from_slice(&fileid.to_be_bytes());
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(&session.to_be_bytes()), &self.psk)
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+        key
+    }
+
+    fn aead(&self, seal: bool, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        let payload = Payload { msg: data, aad: aad };
+        let result = match (self.cipher, seal) {
+            (Cipher::ChaCha20Poly1305, true) => ChaCha20Poly1305::new(key.into()).encrypt(nonce.into(), payload),
+            (Cipher::ChaCha20Poly1305, false) => ChaCha20Poly1305::new(key.into()).decrypt(nonce.into(), payload),
+            (Cipher::Aes256Gcm, true) => Aes256Gcm::new(key.into()).encrypt(nonce.into(), payload),
+            (Cipher::Aes256Gcm, false) => Aes256Gcm::new(key.into()).decrypt(nonce.into(), payload),
+        };
+        result.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "authentication failed"))
+    }
+}
+
+fn associated_data(header: &packet::EftPacketHeader, security_header: &[u8]) -> Vec<u8> {
+    let mut aad = header.raw().to_vec();
+    aad.extend_from_slice(security_header);
+    aad
+}
+
+fn nonce(offset: u16, session: u64, seq: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[0..2].copy_from_slice(&offset.to_be_bytes());
+    nonce[2..4].copy_from_slice(&(session as u16).to_be_bytes());
+    nonce[4..12].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_replay() {
+        let mut w = Window::default();
+        for seq in [100, 102, 101].iter() {
+            assert!(w.check(*seq));
+            w.update(*seq);
+            assert!(!w.check(*seq));
+        }
+        // behind the highest but inside the window, once
+        assert!(!w.check(102 - REPLAY_WINDOW));
+        assert!(w.check(102 - (REPLAY_WINDOW - 1)));
+        w.update(102 - (REPLAY_WINDOW - 1));
+        assert!(!w.check(102 - (REPLAY_WINDOW - 1)));
+    }
+
+    #[test]
+    fn window_slides() {
+        let mut w = Window::default();
+        w.update(100);
+        w.update(99);
+        assert!(!w.check(100 - REPLAY_WINDOW));
+        w.update(130);
+        assert!(!w.check(99) && !w.check(100));
+        assert!(w.check(98) && w.check(131));
+        assert!(!w.check(130 - REPLAY_WINDOW));
+        // a jump beyond the window forgets everything behind it
+        w.update(130 + REPLAY_WINDOW);
+        assert_eq!(w.bitmap, 1);
+        assert!(w.check(131) && !w.check(130));
+    }
+
+    #[test]
+    fn nonce_layout() {
+        let n = nonce(0x0102, 0x1111_2222_3333_0304, 0x0506_0708_090a_0b0c);
+        assert_eq!(n, [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c]);
+    }
+
+    #[test]
+    fn open_once() {
+        let config = CryptoConfig::new(Cipher::ChaCha20Poly1305, &[7; 32]).unwrap();
+        let (sender, receiver) = (Keyring::new(&config), Keyring::new(&config));
+        let (a, b) = (MacAddr::new(2, 0, 0, 0, 0, 1), MacAddr::new(2, 0, 0, 0, 0, 2));
+        sender.originate(b, 5);
+        let p = packet::EftPacket {
+            header: packet::EftPacketHeader {
+                packet_type: packet::EftType::Data as u8,
+                length: 8,
+                total_length: 11,
+                id: 5,
+                offset: 3,
+            },
+            payload: vec![1, 2, 3],
+        };
+        let sealed = sender.seal(a, b, &p).unwrap();
+        assert_eq!(receiver.open(a, b, &sealed).unwrap().payload, p.payload);
+        assert_eq!(receiver.open(a, b, &sealed).err().map(|e| e.to_string()).as_deref(), Some("replayed packet"));
+
+        let mut tampered = sender.seal(a, b, &p).unwrap();
+        tampered.header.offset = 4; // authenticated, and part of the nonce
+        assert!(receiver.open(a, b, &tampered).is_err());
+        assert!(receiver.open(b, a, &sender.seal(a, b, &p).unwrap()).is_err());
+    }
+}
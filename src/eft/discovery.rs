@@ -0,0 +1,181 @@
+use std::{
+    ffi::CStr,
+    io,
+    time,
+};
+
+use pnet::util::MacAddr;
+
+use crate::general;
+
+// Announce payload:
+//
+//   role (1) | capabilities (2, big endian) | name length (1) | name
+//
+// Bound interfaces broadcast an Announce every ANNOUNCE_INTERVAL and as soon as
+// they hear a Solicit, which Interface::discover broadcasts before it listens.
+// The MAC address is the frame's source. Announce and Solicit carry distinct
+// fileids so that their sessions stay apart under a pre-shared key.
+
+pub const BROADCAST: MacAddr = MacAddr(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+
+// capabilities
+pub const CRYPTO: u16 = 1 << 0;
+pub const COMPRESSION: u16 = 1 << 1;
+pub const SERVE: u16 = 1 << 2; // answers Request, List and Stat
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Role {
+    Sender = 0,
+    Receiver = 1,
+    Bidirectional = 2, // InterfacePeer
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub mac: MacAddr,
+    pub name: String,
+    pub role: Role,
+    pub capabilities: u16,
+}
+
+impl Peer {
+    pub fn from_raw(mac: MacAddr, raw: &[u8]) -> io::Result<Self> {
+        if raw.len() < 4 || raw.len() < 4 + raw[3] as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "announce parse error"));
+        }
+        let role = match raw[0] {
+            0 => Role::Sender,
+            1 => Role::Receiver,
+            2 => Role::Bidirectional,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "announce parse error")),
+        };
+        let name = String::from_utf8(raw[4..4 + raw[3] as usize].to_vec()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "announce parse error")
+        })?;
+        Ok(Self {
+            mac: mac,
+            name: name,
+            role: role,
+            capabilities: u16::from_be_bytes([raw[1], raw[2]]),
+        })
+    }
+
+    pub fn raw(&self) -> Vec<u8> {
+        // cut to the length field, on a character boundary
+        let mut length = self.name.len().min(u8::MAX as usize);
+        while !self.name.is_char_boundary(length) {
+            length -= 1;
+        }
+        let name = &self.name.as_bytes()[..length];
+        let mut raw: Vec<u8> = vec![self.role as u8];
+        raw.extend_from_slice(&self.capabilities.to_be_bytes());
+        raw.push(name.len() as u8);
+        raw.extend_from_slice(name);
+        raw
+    }
+}
+
+// decides when a bound interface announces itself
+pub struct Announcer {
+    local: Peer,
+    last: Option<time::Instant>,
+    solicited: bool,
+}
+
+impl Announcer {
+    pub fn new(mac: MacAddr, role: Role, capabilities: u16) -> Self {
+        Self {
+            local: Peer {
+                mac: mac,
+                name: hostname(),
+                role: role,
+                capabilities: capabilities,
+            },
+            last: None,
+            solicited: false,
+        }
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.local.mac
+    }
+
+    // a Solicit was heard
+    pub fn solicit(&mut self) {
+        self.solicited = true;
+    }
+
+    pub fn set_capability(&mut self, capability: u16, on: bool) {
+        if on {
+            self.local.capabilities |= capability;
+        } else {
+            self.local.capabilities &= !capability;
+        }
+    }
+
+    // the Announce payload when one is due
+    pub fn due(&mut self) -> Option<Vec<u8>> {
+        let periodic = self.last.map_or(true, |last| last.elapsed().as_millis() > general::ANNOUNCE_INTERVAL as u128);
+        if !periodic && !self.solicited {
+            return None;
+        }
+        self.last = Some(time::Instant::now());
+        self.solicited = false;
+        Some(self.local.raw())
+    }
+}
+
+pub fn hostname() -> String {
+    let mut name = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr(), name.len()) } != 0 {
+        return String::new();
+    }
+    name[name.len() - 1] = 0;
+    unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr(2, 0, 0, 0, 0, 1);
+
+    fn peer(name: &str) -> Peer {
+        Peer {
+            mac: MAC,
+            name: String::from(name),
+            role: Role::Bidirectional,
+            capabilities: CRYPTO | SERVE,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for name in ["", "host", "hôte"].iter() {
+            assert_eq!(Peer::from_raw(MAC, &peer(name).raw()).unwrap(), peer(name));
+        }
+        // names too long for the length field are cut, never inside a character
+        let long = "é".repeat(200);
+        let parsed = Peer::from_raw(MAC, &peer(&long).raw()).unwrap();
+        assert_eq!(parsed.name, "é".repeat(127));
+    }
+
+    #[test]
+    fn truncated() {
+        let raw = peer("host").raw();
+        for length in 0..raw.len() {
+            assert!(Peer::from_raw(MAC, &raw[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut raw = peer("host").raw();
+        raw[0] = 3;
+        assert!(Peer::from_raw(MAC, &raw).is_err());
+        let mut raw = peer("hôte").raw();
+        raw[5] = 0xff;
+        assert!(Peer::from_raw(MAC, &raw).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    io,
+};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::general;
+use crate::utils;
+
+// 0                   1                   2                   3
+// 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |           Fragments           |          Last Length          |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |             Block             |  Data Shards  | Parity Shards |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |     Index     |   Reserved    |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//
+//        Parity packet payload header, followed by the parity shard
+//
+// Every block of `Data Shards` consecutive fragments (the last block may be
+// shorter) carries `Parity Shards` Reed-Solomon parity fragments. Parity is sent
+// once and never acknowledged; whatever it cannot repair is left to ARQ.
+
+#[derive(Debug, Copy, Clone)]
+pub struct FecConfig {
+    data_shards: usize,
+    parity_shards: usize,
+}
+
+impl FecConfig {
+    // redundancy ratio is parity_shards / data_shards
+    #[allow(dead_code)]
+    pub fn new(data_shards: usize, parity_shards: usize) -> io::Result<Self> {
+        if data_shards == 0 || parity_shards == 0 || data_shards + parity_shards > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid fec config"));
+        }
+        Ok(Self {
+            data_shards: data_shards,
+            parity_shards: parity_shards,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ParityHeader {
+    pub fragments: u16,
+    pub last_length: u16,
+    pub block: u16,
+    pub data_shards: u8,
+    pub parity_shards: u8,
+    pub index: u8,
+}
+
+impl ParityHeader {
+    pub fn from_raw(raw_header: &[u8]) -> io::Result<Self> {
+        if raw_header.len() < general::FEC_HEADER_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::Other, "parse error"));
+        }
+        let header = Self {
+            fragments: u16::from_be_bytes([raw_header[0], raw_header[1]]),
+            last_length: u16::from_be_bytes([raw_header[2], raw_header[3]]),
+            block: u16::from_be_bytes([raw_header[4], raw_header[5]]),
+            data_shards: raw_header[6],
+            parity_shards: raw_header[7],
+            index: raw_header[8],
+        };
+        if header.data_shards == 0 || header.index >= header.parity_shards {
+            return Err(io::Error::new(io::ErrorKind::Other, "parse error"));
+        }
+        Ok(header)
+    }
+
+    pub fn raw(&self) -> [u8; general::FEC_HEADER_LENGTH] {
+        let mut raw_header = [0; general::FEC_HEADER_LENGTH];
+        raw_header[0..2].copy_from_slice(&self.fragments.to_be_bytes());
+        raw_header[2..4].copy_from_slice(&self.last_length.to_be_bytes());
+        raw_header[4..6].copy_from_slice(&self.block.to_be_bytes());
+        raw_header[6] = self.data_shards;
+        raw_header[7] = self.parity_shards;
+        raw_header[8] = self.index;
+        raw_header
+    }
+
+    // data offsets covered by this block
+    fn offsets(&self) -> std::ops::Range<usize> {
+        let start = self.block as usize * self.data_shards as usize;
+        let end = (start + self.data_shards as usize).min(self.fragments as usize);
+        start..end
+    }
+}
+
+// returns the parity payloads (header included) of every block
+pub fn encode(config: &FecConfig, data_fragments: &[Vec<u8>]) -> io::Result<Vec<Vec<u8>>> {
+    let mut parities: Vec<Vec<u8>> = Vec::new();
+    let shard_size = match data_fragments.first() {
+        Some(f) => f.len(),
+        None => return Ok(parities),
+    };
+    let last_length = data_fragments.last().unwrap().len();
+
+    for (block, data) in data_fragments.chunks(config.data_shards).enumerate() {
+        let rs = ReedSolomon::new(data.len(), config.parity_shards).map_err(to_io_error)?;
+        let mut shards: Vec<Vec<u8>> = data.iter().map(|f| {
+            let mut shard = f.clone();
+            shard.resize(shard_size, 0);
+            shard
+        }).collect();
+        shards.resize(data.len() + config.parity_shards, vec![0; shard_size]);
+        rs.encode(&mut shards).map_err(to_io_error)?;
+
+        for (index, shard) in shards[data.len()..].iter().enumerate() {
+            let header = ParityHeader {
+                fragments: data_fragments.len() as u16,
+                last_length: last_length as u16,
+                block: block as u16,
+                data_shards: config.data_shards as u8,
+                parity_shards: config.parity_shards as u8,
+                index: index as u8,
+            };
+            let mut parity = header.raw().to_vec();
+            parity.extend_from_slice(shard);
+            parities.push(parity);
+        }
+    }
+    Ok(parities)
+}
+
+struct Block {
+    header: ParityHeader,
+    parity: Vec<Option<Vec<u8>>>,
+}
+
+#[derive(Default)]
+pub struct Decoder {
+    blocks: HashMap<u16, Block>,
+    data_shards: Option<usize>,
+}
+
+impl Decoder {
+    // stores a parity shard and returns the block it belongs to
+    pub fn on_parity(&mut self, payload: &[u8]) -> io::Result<u16> {
+        let header = ParityHeader::from_raw(payload)?;
+        if header.offsets().end > general::MAX_OFFSET_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::Other, "offset error"));
+        }
+        self.data_shards = Some(header.data_shards as usize);
+        let block = self.blocks.entry(header.block).or_insert_with(|| Block {
+            header: header,
+            parity: vec![None; header.parity_shards as usize],
+        });
+        if let Some(shard) = block.parity.get_mut(header.index as usize) {
+            *shard = Some(payload[general::FEC_HEADER_LENGTH..].to_vec());
+        }
+        Ok(header.block)
+    }
+
+    pub fn block_of(&self, offset: u16) -> Option<u16> {
+        self.data_shards.map(|k| (offset as usize / k) as u16)
+    }
+
+    // rebuilds the missing fragments of `block` once enough shards are present.
+    // returns (offset, is last fragment, data) of every recovered fragment
+    pub fn recover(&mut self, block: u16, buffer: &[Vec<u8>], held: &utils::Flags) -> io::Result<Vec<(u16, bool, Vec<u8>)>> {
+        let mut recovered: Vec<(u16, bool, Vec<u8>)> = Vec::new();
+        let (header, missing, parity_cnt) = match self.blocks.get(&block) {
+            Some(b) => {
+                let missing = b.header.offsets().filter(|o| !held.isset(*o).unwrap_or(false)).count();
+                (b.header, missing, b.parity.iter().filter(|p| p.is_some()).count())
+            },
+            None => return Ok(recovered),
+        };
+        if missing == 0 {
+            self.blocks.remove(&block);
+            return Ok(recovered);
+        }
+        if missing > parity_cnt {
+            return Ok(recovered);
+        }
+
+        let b = self.blocks.remove(&block).unwrap();
+        let shard_size = b.parity.iter().flatten().next().unwrap().len();
+        let mut shards: Vec<Option<Vec<u8>>> = header.offsets().map(|o| {
+            if held.isset(o).unwrap_or(false) {
+                let mut shard = buffer[o].clone();
+                shard.resize(shard_size, 0);
+                Some(shard)
+            } else {
+                None
+            }
+        }).collect();
+        let data_cnt = shards.len();
+        shards.extend(b.parity);
+
+        let rs = ReedSolomon::new(data_cnt, header.parity_shards as usize).map_err(to_io_error)?;
+        rs.reconstruct_data(&mut shards).map_err(to_io_error)?;
+
+        for (offset, shard) in header.offsets().zip(shards) {
+            if held.isset(offset)? {
+                continue;
+            }
+            let mut data = shard.unwrap();
+            let last = offset + 1 == header.fragments as usize;
+            if last {
+                data.truncate(header.last_length as usize);
+            }
+            recovered.push((offset as u16, last, data));
+        }
+        Ok(recovered)
+    }
+}
+
+fn to_io_error(e: reed_solomon_erasure::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("fec error: {:?}", e))
+}
+
+pub struct Encoder {
+    config: FecConfig,
+    parity: Vec<Vec<u8>>,
+    sent: Vec<bool>,
+}
+
+impl Encoder {
+    pub fn new(config: FecConfig, data_fragments: &[Vec<u8>]) -> io::Result<Self> {
+        let parity = encode(&config, data_fragments)?;
+        let blocks = (data_fragments.len() + config.data_shards - 1) / config.data_shards;
+        Ok(Self {
+            config: config,
+            parity: parity,
+            sent: vec![false; blocks],
+        })
+    }
+
+    // parity of the block that `offset` completes, only on its first transmission
+    pub fn parity_for(&mut self, offset: u16, fragments: usize) -> &[Vec<u8>] {
+        let offset = offset as usize;
+        let block = offset / self.config.data_shards;
+        if (offset + 1) % self.config.data_shards != 0 && offset + 1 != fragments {
+            return &[];
+        }
+        match self.sent.get_mut(block) {
+            Some(sent) if !*sent => *sent = true,
+            _ => return &[],
+        }
+        let m = self.config.parity_shards;
+        &self.parity[block * m..(block + 1) * m]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 7 fragments of 10 bytes, the last one of 4
+    fn fragments() -> Vec<Vec<u8>> {
+        (0..7u8).map(|i| vec![i; if i == 6 { 4 } else { 10 }]).collect()
+    }
+
+    // the decoder fed with every parity shard and the fragments not in `lost`
+    fn receive(lost: &[usize]) -> (Decoder, Vec<Vec<u8>>, utils::Flags) {
+        let config = FecConfig::new(3, 2).unwrap();
+        let fragments = fragments();
+        let mut decoder = Decoder::default();
+        for parity in encode(&config, &fragments).unwrap() {
+            decoder.on_parity(&parity).unwrap();
+        }
+        let mut buffer = vec![Vec::new(); fragments.len()];
+        let mut held = utils::Flags::new();
+        for (offset, fragment) in fragments.into_iter().enumerate() {
+            if !lost.contains(&offset) {
+                buffer[offset] = fragment;
+                held.set(offset).unwrap();
+            }
+        }
+        (decoder, buffer, held)
+    }
+
+    #[test]
+    fn parity_layout() {
+        let parities = encode(&FecConfig::new(3, 2).unwrap(), &fragments()).unwrap();
+        assert_eq!(parities.len(), 3 * 2);
+        for (i, parity) in parities.iter().enumerate() {
+            let header = ParityHeader::from_raw(parity).unwrap();
+            assert_eq!((header.fragments, header.last_length, header.block, header.index), (7, 4, (i / 2) as u16, (i % 2) as u8));
+            assert_eq!(parity.len(), general::FEC_HEADER_LENGTH + 10);
+        }
+        assert!(ParityHeader::from_raw(&parities[0][..general::FEC_HEADER_LENGTH - 1]).is_err());
+        let mut raw = parities[0].clone();
+        raw[8] = 2; // index beyond the parity shards
+        assert!(ParityHeader::from_raw(&raw).is_err());
+    }
+
+    #[test]
+    fn recover_lost() {
+        let (mut decoder, buffer, held) = receive(&[1, 2, 6]);
+        assert_eq!(decoder.block_of(6), Some(2));
+        let recovered = decoder.recover(0, &buffer, &held).unwrap();
+        assert_eq!(recovered, vec![(1, false, vec![1; 10]), (2, false, vec![2; 10])]);
+        // the last fragment comes back at its own length
+        let recovered = decoder.recover(2, &buffer, &held).unwrap();
+        assert_eq!(recovered, vec![(6, true, vec![6; 4])]);
+        assert!(decoder.recover(1, &buffer, &held).unwrap().is_empty());
+    }
+
+    #[test]
+    fn too_many_lost() {
+        let (mut decoder, buffer, held) = receive(&[3, 4, 5]);
+        assert!(decoder.recover(1, &buffer, &held).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parity_once_per_block() {
+        let fragments = fragments();
+        let mut encoder = Encoder::new(FecConfig::new(3, 2).unwrap(), &fragments).unwrap();
+        assert!(encoder.parity_for(0, fragments.len()).is_empty());
+        assert_eq!(encoder.parity_for(2, fragments.len()).len(), 2);
+        assert!(encoder.parity_for(2, fragments.len()).is_empty());
+        assert_eq!(encoder.parity_for(6, fragments.len()).len(), 2);
+        assert!(FecConfig::new(0, 1).is_err());
+        assert!(FecConfig::new(200, 56).is_err());
+    }
+}
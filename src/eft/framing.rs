@@ -0,0 +1,169 @@
+use std::io;
+
+use pnet::{
+    packet::{
+        ethernet::{
+            EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket,
+        },
+        Packet,
+    },
+    util::MacAddr,
+};
+
+// Ethernet framing of EFT packets, optionally with an 802.1Q tag:
+//
+//   dst (6) | src (6) | [ 0x8100 | pcp (3) | dei (1) | vid (12) ] | ethertype (2) | eft packet
+//
+// Most NICs strip the tag of received frames into packet metadata, so untagged
+// frames of our EtherType are accepted even when a VLAN is configured.
+
+const ETHERNET_HEADER_LENGTH: usize = 14;
+const VLAN_TAG_LENGTH: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Vlan {
+    pub vid: u16,
+    pub pcp: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Framing {
+    ethertype: EtherType,
+    vlan: Option<Vlan>,
+}
+
+impl Framing {
+    pub fn new(ethertype: u16, vlan: Option<Vlan>) -> io::Result<Self> {
+        // smaller values are 802.3 lengths
+        if ethertype < 0x0600 || ethertype == EtherTypes::Vlan.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid ethertype"));
+        }
+        if let Some(v) = vlan {
+            if v.vid == 0 || v.vid >= 0x0fff || v.pcp > 7 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid vlan tag"));
+            }
+        }
+        Ok(Self {
+            ethertype: EtherType(ethertype),
+            vlan: vlan,
+        })
+    }
+
+    pub fn frame_length(&self, packet_length: usize) -> usize {
+        match self.vlan {
+            Some(_) => ETHERNET_HEADER_LENGTH + VLAN_TAG_LENGTH + packet_length,
+            None => ETHERNET_HEADER_LENGTH + packet_length,
+        }
+    }
+
+    pub fn build(&self, buffer: &mut [u8], src_address: MacAddr, dst_address: MacAddr, packet: &[u8]) {
+        let mut frame = MutableEthernetPacket::new(buffer).unwrap();
+        frame.set_source(src_address);
+        frame.set_destination(dst_address);
+        match self.vlan {
+            Some(v) => {
+                let tci = ((v.pcp as u16) << 13) | v.vid;
+                let mut payload: Vec<u8> = Vec::with_capacity(VLAN_TAG_LENGTH + packet.len());
+                payload.extend_from_slice(&tci.to_be_bytes());
+                payload.extend_from_slice(&self.ethertype.0.to_be_bytes());
+                payload.extend_from_slice(packet);
+                frame.set_ethertype(EtherTypes::Vlan);
+                frame.set_payload(&payload);
+            },
+            None => {
+                frame.set_ethertype(self.ethertype);
+                frame.set_payload(packet);
+            },
+        }
+    }
+
+    // the EFT packet carried by `frame`, or None for frames that are not ours
+    pub fn payload<'a>(&self, frame: &'a EthernetPacket) -> Option<&'a [u8]> {
+        let payload = frame.payload();
+        if frame.get_ethertype() == self.ethertype {
+            return Some(payload);
+        }
+        if frame.get_ethertype() != EtherTypes::Vlan || payload.len() < VLAN_TAG_LENGTH {
+            return None;
+        }
+        let vid = u16::from_be_bytes([payload[0], payload[1]]) & 0x0fff;
+        if u16::from_be_bytes([payload[2], payload[3]]) != self.ethertype.0 {
+            return None;
+        }
+        match self.vlan {
+            Some(v) if v.vid != vid => None,
+            _ => Some(&payload[VLAN_TAG_LENGTH..]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: MacAddr = MacAddr(2, 0, 0, 0, 0, 1);
+    const DST: MacAddr = MacAddr(2, 0, 0, 0, 0, 2);
+    const ETHERTYPE: u16 = 0x88b5;
+    const VLAN: Vlan = Vlan { vid: 100, pcp: 5 };
+
+    fn frame(framing: &Framing, packet: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; framing.frame_length(packet.len())];
+        framing.build(&mut buffer, SRC, DST, packet);
+        buffer
+    }
+
+    fn payload(framing: &Framing, frame: &[u8]) -> Option<Vec<u8>> {
+        framing.payload(&EthernetPacket::new(frame).unwrap()).map(|p| p.to_vec())
+    }
+
+    #[test]
+    fn untagged() {
+        let framing = Framing::new(ETHERTYPE, None).unwrap();
+        let raw = frame(&framing, &[1, 2, 3]);
+        assert_eq!(raw.len(), ETHERNET_HEADER_LENGTH + 3);
+        assert_eq!(raw[12..], [0x88, 0xb5, 1, 2, 3]);
+        assert_eq!(payload(&framing, &raw), Some(vec![1, 2, 3]));
+        // untagged frames are ours whatever the vlan, the NIC may have stripped the tag
+        assert_eq!(payload(&Framing::new(ETHERTYPE, Some(VLAN)).unwrap(), &raw), Some(vec![1, 2, 3]));
+        assert_eq!(payload(&Framing::new(ETHERTYPE + 1, None).unwrap(), &raw), None);
+    }
+
+    #[test]
+    fn tagged() {
+        let framing = Framing::new(ETHERTYPE, Some(VLAN)).unwrap();
+        let raw = frame(&framing, &[1, 2, 3]);
+        assert_eq!(raw.len(), ETHERNET_HEADER_LENGTH + VLAN_TAG_LENGTH + 3);
+        assert_eq!(raw[12..], [0x81, 0x00, 0xa0, 100, 0x88, 0xb5, 1, 2, 3]);
+        assert_eq!(payload(&framing, &raw), Some(vec![1, 2, 3]));
+        assert_eq!(payload(&Framing::new(ETHERTYPE, None).unwrap(), &raw), Some(vec![1, 2, 3]));
+        let other = Framing::new(ETHERTYPE, Some(Vlan { vid: 101, pcp: 5 })).unwrap();
+        assert_eq!(payload(&other, &raw), None);
+    }
+
+    #[test]
+    fn malformed_tags() {
+        let framing = Framing::new(ETHERTYPE, Some(VLAN)).unwrap();
+        let raw = frame(&framing, &[]);
+        // cut inside the tag
+        for length in ETHERNET_HEADER_LENGTH..raw.len() {
+            assert_eq!(payload(&framing, &raw[..length]), None, "{}", length);
+        }
+        // an 802.1ad outer tag is not ours
+        let mut wrong_tpid = raw.clone();
+        wrong_tpid[12..14].copy_from_slice(&[0x88, 0xa8]);
+        assert_eq!(payload(&framing, &wrong_tpid), None);
+        // nor is another protocol behind our tag
+        let mut wrong_inner = raw.clone();
+        wrong_inner[16..18].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(payload(&framing, &wrong_inner), None);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Framing::new(0x05ff, None).is_err());
+        assert!(Framing::new(0x8100, None).is_err());
+        for vlan in [Vlan { vid: 0, pcp: 0 }, Vlan { vid: 0x0fff, pcp: 0 }, Vlan { vid: 1, pcp: 8 }].iter() {
+            assert!(Framing::new(ETHERTYPE, Some(*vlan)).is_err());
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    convert::TryFrom,
+    fs::{
+        self, File,
+    },
+    io::{
+        self,
+        Write,
+    },
+    os::unix::fs::{
+        MetadataExt,
+        OpenOptionsExt,
+        PermissionsExt,
+    },
+    path::{
+        Component, Path, PathBuf,
+    },
+    time,
+};
+
+use crate::utils;
+use super::compress;
+
+// Meta packet payload (all integers are big endian):
+//
+//   size (8) | mode (4) | mtime secs (8) | mtime nsecs (4) | path length (2) | path
+//   xattr count (2) | { name length (1) | name | value length (2) | value } ...
+//   codec (1) | level (1) | has digest (1) | [sha-256 (32)]
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub path: String, // relative to the receiver's output directory
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: time::SystemTime,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    pub compression: Option<compress::Compression>, // of the transferred bytes, size is before compression
+    pub digest: Option<[u8; 32]>, // sha-256 before compression, see SendOptions::digest
+}
+
+impl Metadata {
+    pub fn from_path(filepath: &str, name: &str) -> io::Result<Self> {
+        let m = fs::metadata(filepath)?;
+        Ok(Self {
+            path: name.to_string(),
+            size: m.len(),
+            mode: m.mode() & 0o7777,
+            mtime: m.modified()?,
+            xattrs: read_xattrs(filepath)?,
+            compression: None,
+            digest: None,
+        })
+    }
+
+    pub fn from_raw(raw: &[u8]) -> io::Result<Self> {
+        let mut r = utils::Reader::new(raw, "meta parse error");
+        let size = u64::from_be_bytes(r.array()?);
+        let mode = u32::from_be_bytes(r.array()?);
+        let secs = i64::from_be_bytes(r.array()?);
+        let nsecs = u32::from_be_bytes(r.array()?);
+        let path_length = u16::from_be_bytes(r.array()?) as usize;
+        let path = r.string(path_length)?;
+
+        let mut xattrs: Vec<(String, Vec<u8>)> = Vec::new();
+        for _ in 0..u16::from_be_bytes(r.array()?) {
+            let name_length = r.take(1)?[0] as usize;
+            let name = r.string(name_length)?;
+            let value_length = u16::from_be_bytes(r.array()?) as usize;
+            xattrs.push((name, r.take(value_length)?.to_vec()));
+        }
+        let codec = r.take(2)?;
+        let compression = compress::Compression::from_raw(codec[0], codec[1])?;
+        let digest = if r.take(1)?[0] != 0 {
+            Some(r.array::<[u8; 32]>()?)
+        } else {
+            None
+        };
+
+        // from the network: out of range values must not panic
+        if nsecs >= 1_000_000_000 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid mtime"));
+        }
+        let mtime = if secs >= 0 {
+            time::UNIX_EPOCH.checked_add(time::Duration::new(secs as u64, nsecs))
+        } else {
+            time::UNIX_EPOCH.checked_sub(time::Duration::new(secs.unsigned_abs(), 0))
+                .and_then(|t| t.checked_add(time::Duration::new(0, nsecs)))
+        }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid mtime"))?;
+        Ok(Self {
+            path: path,
+            size: size,
+            mode: mode,
+            mtime: mtime,
+            xattrs: xattrs,
+            compression: compression,
+            digest: digest,
+        })
+    }
+
+    pub fn raw(&self) -> io::Result<Vec<u8>> {
+        let (secs, nsecs) = match self.mtime.duration_since(time::UNIX_EPOCH) {
+            Ok(d) => (i64::try_from(d.as_secs()).ok(), d.subsec_nanos()),
+            Err(e) => {
+                let d = e.duration();
+                let secs = 0i64.checked_sub_unsigned(d.as_secs());
+                if d.subsec_nanos() == 0 {
+                    (secs, 0)
+                } else {
+                    (secs.and_then(|secs| secs.checked_sub(1)), 1_000_000_000 - d.subsec_nanos())
+                }
+            },
+        };
+        let secs = secs.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid mtime"))?;
+        if self.path.len() > u16::max_value() as usize || self.xattrs.len() > u16::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "metadata too large"));
+        }
+
+        let mut raw: Vec<u8> = Vec::new();
+        raw.extend_from_slice(&self.size.to_be_bytes());
+        raw.extend_from_slice(&self.mode.to_be_bytes());
+        raw.extend_from_slice(&secs.to_be_bytes());
+        raw.extend_from_slice(&nsecs.to_be_bytes());
+        raw.extend_from_slice(&(self.path.len() as u16).to_be_bytes());
+        raw.extend_from_slice(self.path.as_bytes());
+        raw.extend_from_slice(&(self.xattrs.len() as u16).to_be_bytes());
+        for (name, value) in self.xattrs.iter() {
+            if name.len() > u8::max_value() as usize || value.len() > u16::max_value() as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "metadata too large"));
+            }
+            raw.push(name.len() as u8);
+            raw.extend_from_slice(name.as_bytes());
+            raw.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            raw.extend_from_slice(value);
+        }
+        raw.extend_from_slice(&compress::Compression::raw(&self.compression));
+        match self.digest {
+            Some(digest) => {
+                raw.push(1);
+                raw.extend_from_slice(&digest);
+            },
+            None => raw.push(0),
+        }
+        Ok(raw)
+    }
+
+    // sets permissions, modification time and extended attributes on `filepath`.
+    // the sender is not trusted with setuid, setgid and sticky bits, nor with
+    // attributes outside the user namespace such as security.capability
+    pub fn apply(&self, filepath: &Path) -> io::Result<()> {
+        let f = File::open(filepath)?;
+        f.set_modified(self.mtime)?;
+        let xattrs: Vec<(String, Vec<u8>)> = self.xattrs.iter().filter(|(name, _)| is_user_xattr(name)).cloned().collect();
+        write_xattrs(filepath, &xattrs)?;
+        fs::set_permissions(filepath, fs::Permissions::from_mode(self.mode & 0o777))
+    }
+}
+
+// joins the sender supplied relative path onto `dir`, refusing anything that would escape it
+pub fn target_path(dir: &Path, path: &str) -> io::Result<PathBuf> {
+    let mut target = dir.to_path_buf();
+    let mut empty = true;
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => {
+                target.push(c);
+                empty = false;
+            },
+            Component::CurDir => (),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unsafe path")),
+        }
+    }
+    if empty {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unsafe path"));
+    }
+    Ok(target)
+}
+
+// as target_path, also refusing a path whose directories include a symlink already under
+// `dir`: writing through it could land anywhere
+pub fn create_path(dir: &Path, path: &str) -> io::Result<PathBuf> {
+    let target = target_path(dir, path)?;
+    let mut current = dir.to_path_buf();
+    let components: Vec<Component> = Path::new(path).components().collect();
+    for component in components.iter().take(components.len().saturating_sub(1)) {
+        current.push(component);
+        if fs::symlink_metadata(&current).map_or(false, |m| m.file_type().is_symlink()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "path through a symlink"));
+        }
+    }
+    Ok(target)
+}
+
+// resolves a path asked of a server: as target_path, "" or "." being `root` itself, also refusing
+// a path that leaves `root` through symlinks, which must thus be canonical
+pub fn served_path(root: &Path, path: &str) -> io::Result<PathBuf> {
+    let target = match path {
+        "" | "." => root.to_path_buf(),
+        _ => target_path(root, path)?,
+    };
+    if !fs::canonicalize(&target)?.starts_with(root) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "path outside the served root"));
+    }
+    Ok(target)
+}
+
+// creates or truncates `target`, failing if it is a symlink
+pub fn create_file(target: &Path) -> io::Result<File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(target)
+}
+
+// writes `data` under `dir` at the path carried by the metadata and applies its attributes
+pub fn write_file(dir: &Path, metadata: &Metadata, data: &[u8]) -> io::Result<PathBuf> {
+    let target = create_path(dir, &metadata.path)?;
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    create_file(&target)?.write_all(data)?;
+    metadata.apply(&target)?;
+    Ok(target)
+}
+
+fn is_user_xattr(name: &str) -> bool {
+    name.starts_with("user.")
+}
+
+#[cfg(feature = "xattr")]
+fn read_xattrs(filepath: &str) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs: Vec<(String, Vec<u8>)> = Vec::new();
+    for name in xattr::list(filepath)? {
+        let name = if let Some(n) = name.to_str() {
+            n.to_string()
+        } else {
+            continue
+        };
+        if let Some(value) = xattr::get(filepath, &name)? {
+            xattrs.push((name, value));
+        }
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(feature = "xattr"))]
+fn read_xattrs(_filepath: &str) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+#[cfg(feature = "xattr")]
+fn write_xattrs(filepath: &Path, xattrs: &[(String, Vec<u8>)]) -> io::Result<()> {
+    for (name, value) in xattrs {
+        xattr::set(filepath, name, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "xattr"))]
+fn write_xattrs(_filepath: &Path, _xattrs: &[(String, Vec<u8>)]) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs as unix_fs;
+
+    use super::*;
+
+    fn metadata(mtime: time::SystemTime) -> Metadata {
+        Metadata {
+            path: String::from("a/b"),
+            size: 3,
+            mode: 0o640,
+            mtime: mtime,
+            xattrs: vec![(String::from("user.k"), vec![1, 2])],
+            compression: None,
+            digest: Some([7; 32]),
+        }
+    }
+
+    // overwrites the mtime fields of a raw Meta payload
+    fn with_mtime(secs: i64, nsecs: u32) -> Vec<u8> {
+        let mut raw = metadata(time::UNIX_EPOCH).raw().unwrap();
+        raw[12..20].copy_from_slice(&secs.to_be_bytes());
+        raw[20..24].copy_from_slice(&nsecs.to_be_bytes());
+        raw
+    }
+
+    #[test]
+    fn round_trip() {
+        for mtime in [
+            time::UNIX_EPOCH + time::Duration::new(1_700_000_000, 123),
+            time::UNIX_EPOCH - time::Duration::new(10, 1),
+            time::UNIX_EPOCH,
+        ] {
+            let m = metadata(mtime);
+            assert_eq!(Metadata::from_raw(&m.raw().unwrap()).unwrap(), m);
+        }
+    }
+
+    #[test]
+    fn hostile_mtime() {
+        for (secs, nsecs) in [(0, 1_000_000_000), (-1, u32::MAX), (i64::MAX, u32::MAX)] {
+            let e = Metadata::from_raw(&with_mtime(secs, nsecs)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+        // representable or not depending on the platform, but never a panic
+        for (secs, nsecs) in [(i64::MAX, 0), (i64::MAX, 999_999_999), (i64::MIN, 0), (i64::MIN, 999_999_999)] {
+            match Metadata::from_raw(&with_mtime(secs, nsecs)) {
+                Ok(m) => assert_eq!(Metadata::from_raw(&m.raw().unwrap()).unwrap(), m),
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            }
+        }
+    }
+
+    #[test]
+    fn untrusted_attributes() {
+        let path = std::env::temp_dir().join(format!("robust-meta-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let mut m = metadata(time::UNIX_EPOCH);
+        m.mode = 0o7755;
+        m.apply(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o7777, 0o755);
+        fs::remove_file(&path).unwrap();
+
+        assert!(is_user_xattr("user.k"));
+        for name in ["security.capability", "trusted.overlay.opaque", "system.posix_acl_access", "user"].iter() {
+            assert!(!is_user_xattr(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn served_paths() {
+        let root = std::env::temp_dir().join(format!("robust-served-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("d")).unwrap();
+        File::create(root.join("d/f")).unwrap();
+        unix_fs::symlink("d/f", root.join("inside")).unwrap();
+        unix_fs::symlink("/etc", root.join("out")).unwrap();
+        unix_fs::symlink("..", root.join("d/up")).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+        for path in ["", ".", "d", "d/f", "inside", "d/up/d/f"].iter() {
+            assert!(served_path(&root, path).is_ok(), "{}", path);
+        }
+        assert_eq!(served_path(&root, "inside").unwrap(), root.join("inside"));
+        for path in ["out", "out/passwd", "d/up/..", "../etc", "missing"].iter() {
+            assert!(served_path(&root, path).is_err(), "{}", path);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let raw = metadata(time::UNIX_EPOCH).raw().unwrap();
+        for length in 0..raw.len() {
+            assert!(Metadata::from_raw(&raw[..length]).is_err());
+        }
+    }
+}
@@ -1,9 +1,24 @@
 use std::{
     collections::{
-        BTreeMap, hash_map::Entry, HashMap,
+        hash_map::Entry, HashMap, HashSet, VecDeque,
+    },
+    error,
+    fmt,
+    fs::{
+        self, File,
+    },
+    io::{
+        self,
+        Write,
+    },
+    mem,
+    path::{
+        Path, PathBuf,
     },
-    io::self,
     sync::{
+        atomic::{
+            AtomicBool, Ordering,
+        },
         Arc, Condvar, Mutex, mpsc,
     },
     thread,
@@ -16,14 +31,36 @@ use pnet::{
     },
     packet::{
         ethernet::{
-            MutableEthernetPacket, EtherType, EthernetPacket,
+            EthernetPacket,
         },
-        Packet,
     },
     util::MacAddr,
 };
+use sha2::{
+    Digest, Sha256,
+};
 
+pub mod compress;
+pub mod config;
+pub mod crypto;
+pub mod discovery;
+pub mod fec;
+pub mod framing;
+pub mod meta;
+mod multicast;
 pub mod packet;
+mod pacing;
+mod progress;
+pub mod remote;
+#[cfg(target_os = "linux")]
+mod ring;
+mod resume;
+mod sched;
+pub mod tree;
+
+pub use self::pacing::RateLimit;
+pub use self::progress::{Progress, Status};
+pub use self::sched::Priority;
 
 use super::general;
 use super::utils;
@@ -39,6 +76,10 @@ struct Tri {
 struct InternalInterfaceRecvModeHandle {
     recv_manager: Mutex<RecvConnectionManager>,
     rcv_cv: Condvar,
+    crypto: Option<Arc<crypto::Keyring>>, // the receive loop's
+    tuning: config::Tuning,
+    stop: Arc<Stop>, // shared with the Workers
+    stopped: AtomicBool, // the receive loop has exited
 }
 
 type InterfaceRecvModeHandle = Arc<InternalInterfaceRecvModeHandle>;
@@ -47,34 +88,209 @@ type InterfaceRecvModeHandle = Arc<InternalInterfaceRecvModeHandle>;
 pub struct InterfaceRecvMode {
     ih: InterfaceRecvModeHandle,
     dst: MacAddr,
+    send: Option<InterfaceSendModeHandle>, // the send side of an InterfacePeer
+    workers: Arc<Workers>,
 }
 
 impl InterfaceRecvMode {
     pub fn stream(&mut self, fileid: u16, src: MacAddr) -> io::Result<RecvStream> {
+        self.check_fileid(src, fileid)?;
         let mut cm = self.ih.recv_manager.lock().unwrap();
         let tri = Tri {
             src: src,
             dst: self.dst,
             fileid: fileid,
         };
-        cm.connections.insert(
-            tri, 
-            RecvConnection {
-                buffer: vec![Default::default(); general::MAX_OFFSET_LENGTH],
-                flag4buffer: utils::Flags::new(),
-                cnt: 0,
+        // a fileid is reused once the sender released the previous transfer on it
+        while cm.connections.get(&tri).map_or(false, |c| c.closed) {
+            cm = self.ih.rcv_cv.wait(cm).unwrap();
+        }
+        let generation = cm.open(tri, RecvConnection::new());
+        Ok(RecvStream{
+            tri: tri,
+            generation: generation,
+            ih: self.ih.clone(),
+            metadata: None,
+        })
+    }
+
+    // receives into `filepath`, keeping partial data in a sidecar file so that the
+    // transfer resumes where it stopped after either side restarts
+    #[allow(dead_code)]
+    pub fn stream_to(&mut self, fileid: u16, src: MacAddr, filepath: String) -> io::Result<RecvStream> {
+        self.check_fileid(src, fileid)?;
+        let mut cm = self.ih.recv_manager.lock().unwrap();
+        let tri = Tri {
+            src: src,
+            dst: self.dst,
+            fileid: fileid,
+        };
+        while cm.connections.get(&tri).map_or(false, |c| c.closed) {
+            cm = self.ih.rcv_cv.wait(cm).unwrap();
+        }
+        let (sidecar, fragments) = resume::Sidecar::open(&filepath, src, fileid)?;
+        let mut connection = RecvConnection::new();
+        connection.unconfirmed = !fragments.is_empty();
+        for fragment in fragments {
+            if fragment.packet_type == packet::EftType::Meta as u8 {
+                connection.on_meta(&fragment.data)?;
+            } else {
+                connection.on_packet(fragment.offset, fragment.packet_type, &fragment.data)?;
+            }
+        }
+        connection.resumed = connection.cnt > 0;
+        connection.sidecar = Some(sidecar);
+        connection.destination = Some(filepath);
+        let generation = cm.open(tri, connection);
+        Ok(RecvStream{
+            tri: tri,
+            generation: generation,
+            ih: self.ih.clone(),
+            metadata: None,
+        })
+    }
+
+    // receives a tree sent with send_dir and recreates it under `root`.
+    // returns the paths of the files written
+    #[allow(dead_code)]
+    pub fn recv_dir(&mut self, fileid: u16, src: MacAddr, root: String) -> io::Result<Vec<PathBuf>> {
+        let root = Path::new(&root);
+        let manifest = tree::Manifest::from_raw(&self.stream(fileid, src)?.read()?)?;
+        manifest.create_dirs(root)?;
+
+        let mut streams: Vec<(tree::Entry, RecvStream)> = Vec::new();
+        for e in manifest.entries.iter().filter(|e| e.kind == tree::EntryKind::File) {
+            streams.push((e.clone(), self.stream(e.fileid, src)?));
+        }
+        let mut written: Vec<PathBuf> = Vec::new();
+        for (e, mut stream) in streams {
+            let data = stream.read()?;
+            let target = meta::create_path(root, &e.path)?;
+            tree::write_file(&target, &data, e.sparse)?;
+            if let Some(metadata) = stream.metadata() {
+                metadata.apply(&target)?;
             }
-        );
+            written.push(target);
+        }
+
+        manifest.create_symlinks(root)?;
+        manifest.finish_dirs(root)?;
+        Ok(written)
+    }
+
+    // joins a one-to-many transfer that `src` multicasts to `group`
+    #[allow(dead_code)]
+    pub fn stream_multicast(&mut self, fileid: u16, src: MacAddr, group: MacAddr) -> io::Result<RecvStream> {
+        if !group.is_multicast() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a multicast address"));
+        }
+        let mut cm = self.ih.recv_manager.lock().unwrap();
+        let tri = Tri {
+            src: src,
+            dst: group,
+            fileid: fileid,
+        };
+        let mut connection = RecvConnection::new();
+        connection.multicast = Some(multicast::RecvGroup::new(group, self.dst));
+        let generation = cm.open(tri, connection);
         Ok(RecvStream{
             tri: tri,
+            generation: generation,
             ih: self.ih.clone(),
+            metadata: None,
         })
     }
+
+    // stops the worker threads, see Workers::shutdown
+    #[allow(dead_code)]
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.workers.shutdown()
+    }
+
+    // reports of every receive go to `progress`, None stops them
+    #[allow(dead_code)]
+    pub fn set_progress(&self, progress: Option<mpsc::Sender<Progress>>) {
+        self.ih.recv_manager.lock().unwrap().progress = progress;
+    }
+
+    // asks `server` for `path` under the directory it serves, see InterfacePeer::serve.
+    // the file arrives under `fileid`, a missing or unreadable one resets the stream
+    #[allow(dead_code)]
+    pub fn request(&mut self, fileid: u16, server: MacAddr, path: &str) -> io::Result<RecvStream> {
+        self.ask(packet::EftType::Request, fileid, server, path)
+    }
+
+    // the entries of directory `path` on `server`, received under `fileid`
+    #[allow(dead_code)]
+    pub fn list(&mut self, fileid: u16, server: MacAddr, path: &str) -> io::Result<Vec<remote::Stat>> {
+        remote::listing_from_raw(&self.ask(packet::EftType::List, fileid, server, path)?.read()?)
+    }
+
+    // size, mode, mtime and for regular files the sha-256 digest of `path` on `server`
+    #[allow(dead_code)]
+    pub fn stat(&mut self, fileid: u16, server: MacAddr, path: &str) -> io::Result<remote::Stat> {
+        remote::Stat::from_raw(&self.ask(packet::EftType::Stat, fileid, server, path)?.read()?)
+    }
+
+    fn ask(&mut self, packet_type: packet::EftType, fileid: u16, server: MacAddr, path: &str) -> io::Result<RecvStream> {
+        self.check_fileid(server, fileid)?;
+        let mut cm = self.ih.recv_manager.lock().unwrap();
+        let tri = Tri {
+            src: server,
+            dst: self.dst,
+            fileid: fileid,
+        };
+        if let Some(keyring) = self.ih.crypto.as_ref() { // requests go out before the server has a session
+            keyring.solicit(server, fileid);
+        }
+        let mut connection = RecvConnection::new();
+        let backdate = time::Duration::from_millis(self.ih.tuning.backdate);
+        connection.request = Some((packet_type, path.as_bytes().to_vec(), time::Instant::now() - backdate));
+        connection.last_heard = Some(time::Instant::now()); // the server is expected to answer
+        let generation = cm.open(tri, connection);
+        Ok(RecvStream{
+            tri: tri,
+            generation: generation,
+            ih: self.ih.clone(),
+            metadata: None,
+        })
+    }
+
+    // a peer uses a fileid in one direction at a time, or its Fin and Reset would be ambiguous
+    fn check_fileid(&self, src: MacAddr, fileid: u16) -> io::Result<()> {
+        let tri = Tri {
+            src: self.dst,
+            dst: src,
+            fileid: fileid,
+        };
+        match self.send.as_ref() {
+            Some(ih) if ih.send_manager.lock().unwrap().connections.contains_key(&tri) => {
+                Err(io::Error::new(io::ErrorKind::AlreadyExists, "fileid in use for sending"))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SendOptions {
+    pub fec: Option<fec::FecConfig>,
+    pub symlinks: tree::SymlinkPolicy, // send_dir only
+    pub compression: Option<compress::Compression>,
+    pub priority: Priority,
+    pub max_retries: Option<usize>, // general::MAX_RETRIES by default
+    pub deadline: Option<time::Duration>, // from the moment the send is queued
+    pub digest: bool, // carry a sha-256 of the file in Meta, checked by the receiver
 }
 
 #[derive(Default)]
 struct InternalInterfaceSendModeHandle {
     send_manager: Mutex<SendConnectionManager>,
+    probe_cv: Condvar,
+    crypto: Option<Arc<crypto::Keyring>>,
+    tuning: config::Tuning,
+    stop: Arc<Stop>, // shared with the Workers
+    stopped: AtomicBool, // the send loop has exited
 }
 
 type InterfaceSendModeHandle = Arc<InternalInterfaceSendModeHandle>;
@@ -83,234 +299,1223 @@ type InterfaceSendModeHandle = Arc<InternalInterfaceSendModeHandle>;
 pub struct InterfaceSendMode {
     ih: InterfaceSendModeHandle,
     src: MacAddr,
-    // jh: thread::JoinHandle<io::Result<()>>, TODO
+    mtu: usize,
+    recv: Option<InterfaceRecvModeHandle>, // the receive side of an InterfacePeer
+    workers: Arc<Workers>,
 }
 
 impl InterfaceSendMode {
+    // as reported by the bound interface
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    // finds the largest packet that reaches `dst` by a binary search with padded probes.
+    // probes that are never echoed, e.g. dropped by a switch in between, count as too large
     #[allow(dead_code)]
-    pub fn send(&mut self, fileid: u16, dst: MacAddr, filepath: String, mtu: usize) -> io::Result<()> {
+    pub fn probe_mtu(&self, dst: MacAddr) -> io::Result<usize> {
+        if let Some(keyring) = self.ih.crypto.as_ref() {
+            keyring.originate(dst, general::PROBE_FILEID);
+        }
+        let (mut lo, mut hi) = (general::DEFAULT_MTU.min(self.mtu), self.mtu);
+        while lo < hi {
+            let mid = (lo + hi + 1) / 2;
+            if self.probe(dst, mid) {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        Ok(lo)
+    }
+
+    fn probe(&self, dst: MacAddr, size: usize) -> bool {
+        let mut cm = self.ih.send_manager.lock().unwrap();
+        cm.probed.remove(&dst);
+        for _ in 0..general::MAX_PROBE_RETRIES {
+            cm.probes.push((EndPoint { src: self.src, dst: dst, }, size));
+            let deadline = time::Instant::now() + time::Duration::from_millis(general::PROBE_TIMEOUT);
+            loop {
+                if cm.probed.get(&dst).map_or(false, |probed| *probed >= size) {
+                    return true;
+                }
+                let now = time::Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                cm = self.ih.probe_cv.wait_timeout(cm, deadline - now).unwrap().0;
+            }
+        }
+        false
+    }
+
+    // caps the rate of all transfers, None lifts the limit. takes effect immediately
+    #[allow(dead_code)]
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.ih.send_manager.lock().unwrap().limits.set_interface(limit);
+    }
+
+    // caps the rate of transfers to `dst`, on top of the interface limit
+    #[allow(dead_code)]
+    pub fn set_peer_rate_limit(&self, dst: MacAddr, limit: Option<RateLimit>) {
+        self.ih.send_manager.lock().unwrap().limits.set_peer(dst, limit);
+    }
+
+    // stops the worker threads, see Workers::shutdown
+    #[allow(dead_code)]
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.workers.shutdown()
+    }
+
+    // reports of every send go to `progress`, None stops them
+    #[allow(dead_code)]
+    pub fn set_progress(&self, progress: Option<mpsc::Sender<Progress>>) {
+        self.ih.send_manager.lock().unwrap().progress = progress;
+    }
+
+    // cancels every send to `dst` in flight when CancelToken::cancel is called,
+    // a multicast group address for multicast sends
+    #[allow(dead_code)]
+    pub fn cancel_token(&self, dst: MacAddr) -> CancelToken {
+        CancelToken { target: Cancel::Destination(self.ih.clone(), dst) }
+    }
+
+    // the largest file a transfer at `mtu` carries, as it is once compressed
+    #[allow(dead_code)]
+    pub fn max_size(&self, mtu: usize, options: &SendOptions) -> io::Result<u64> {
+        self.check_mtu(mtu)?;
+        let mut overhead = general::EFT_HEADER_LENGTH;
+        if self.ih.crypto.is_some() {
+            overhead += general::AEAD_OVERHEAD;
+        }
+        if options.fec.is_some() {
+            overhead += general::FEC_HEADER_LENGTH;
+        }
+        Ok(((mtu - overhead) * self.ih.tuning.max_fragments) as u64)
+    }
+
+    fn check_mtu(&self, mtu: usize) -> io::Result<()> {
+        let overhead = general::EFT_HEADER_LENGTH + general::FEC_HEADER_LENGTH + general::AEAD_OVERHEAD;
+        if mtu > self.mtu || mtu <= overhead {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "mtu out of range"));
+        }
+        Ok(())
+    }
+
+    // sends `data` as a file named `name`
+    fn send_bytes(&mut self, fileid: u16, dst: MacAddr, data: &[u8], name: &str, mtu: usize, options: &SendOptions) -> io::Result<SendHandle> {
+        self.check_fileid(dst, fileid)?;
+        let metadata = meta::Metadata {
+            path: name.to_string(),
+            size: data.len() as u64,
+            mode: 0o644,
+            mtime: time::SystemTime::now(),
+            xattrs: Vec::new(),
+            compression: None,
+            digest: None,
+        };
         let mut cm = self.ih.send_manager.lock().unwrap();
         let tri = Tri {
             src: self.src,
             dst: dst,
             fileid: fileid,
         };
-        
-        let data_fragments = utils::split_file(&filepath, mtu)?;
-        let mut buffer: Vec<packet::EftPacket> = Vec::new();
-        let mut send_timers: Vec<time::Instant> = Vec::new();
-        let timer_init = time::Instant::now() - time::Duration::new(5, 0);
-        for (offset, data_fragment) in data_fragments.iter().enumerate() {
-            let packet = packet::EftPacket {
-                header: packet::EftPacketHeader {
-                    packet_type:
-                        if data_fragments.len() - 1 == offset {
-                            packet::EftType::DataEnd as u8
-                        } else {
-                            packet::EftType::Data as u8
-                        },
-                    length: 8,
-                    total_length: data_fragment.len() as u16 + 8,
-                    id: tri.fileid,
-                    offset: offset as u16,
-                },
-                payload: data_fragment.to_vec(),
+        let (connection, handle) = SendConnection::new(tri, &self.ih, data, &metadata, mtu, self.ih.tuning.bulk_rto, options)?;
+        cm.connections.insert(tri, connection);
+        Ok(handle)
+    }
+
+    // see InterfaceRecvMode::check_fileid
+    fn check_fileid(&self, dst: MacAddr, fileid: u16) -> io::Result<()> {
+        let tri = Tri {
+            src: dst,
+            dst: self.src,
+            fileid: fileid,
+        };
+        match self.recv.as_ref() {
+            Some(ih) if ih.recv_manager.lock().unwrap().connections.contains_key(&tri) => {
+                Err(io::Error::new(io::ErrorKind::AlreadyExists, "fileid in use for receiving"))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn send(&mut self, fileid: u16, dst: MacAddr, filepath: String, mtu: usize) -> io::Result<SendHandle> {
+        self.send_with(fileid, dst, filepath, mtu, &SendOptions::default())
+    }
+
+    #[allow(dead_code)]
+    pub fn send_with(&mut self, fileid: u16, dst: MacAddr, filepath: String, mtu: usize, options: &SendOptions) -> io::Result<SendHandle> {
+        self.check_mtu(mtu)?;
+        self.check_fileid(dst, fileid)?;
+        let mut cm = self.ih.send_manager.lock().unwrap();
+        let tri = Tri {
+            src: self.src,
+            dst: dst,
+            fileid: fileid,
+        };
+        let (connection, handle) = SendConnection::from_file(tri, &self.ih, &filepath, &default_name(&filepath), mtu, self.ih.tuning.rto, options)?;
+        cm.connections.insert(tri, connection);
+        Ok(handle)
+    }
+
+    #[allow(dead_code)]
+    pub fn send_files(&mut self, fileids: Vec<u16>, dst: MacAddr, filepaths: Vec<String>, mtu: usize) -> io::Result<Vec<SendHandle>> {
+        self.send_files_with(fileids, dst, filepaths, mtu, &SendOptions::default())
+    }
+
+    #[allow(dead_code)]
+    pub fn send_files_with(&mut self, fileids: Vec<u16>, dst: MacAddr, filepaths: Vec<String>, mtu: usize, options: &SendOptions) -> io::Result<Vec<SendHandle>> {
+        self.check_mtu(mtu)?;
+        for fileid in fileids.iter() {
+            self.check_fileid(dst, *fileid)?;
+        }
+        // all or nothing: a file that cannot be sent leaves the others unsent
+        let mut connections: Vec<(Tri, SendConnection)> = Vec::new();
+        let mut handles: Vec<SendHandle> = Vec::new();
+        for i in 0..fileids.len() {
+            let tri = Tri {
+                src: self.src,
+                dst: dst,
+                fileid: fileids[i],
             };
-            buffer.push(packet);
-            send_timers.push(timer_init);
+            let (connection, handle) = SendConnection::from_file(tri, &self.ih, &filepaths[i], &default_name(&filepaths[i]), mtu, self.ih.tuning.bulk_rto, options)?;
+            connections.push((tri, connection));
+            handles.push(handle);
+        }
+        self.ih.send_manager.lock().unwrap().connections.extend(connections);
+        Ok(handles)
+    }
+
+    // sends a manifest of the tree under `fileid`, then every file with the following fileids
+    #[allow(dead_code)]
+    pub fn send_dir(&mut self, fileid: u16, dst: MacAddr, dirpath: String, mtu: usize, options: &SendOptions) -> io::Result<Vec<SendHandle>> {
+        self.check_mtu(mtu)?;
+        let (manifest, files) = tree::Manifest::walk(Path::new(&dirpath), fileid, options.symlinks)?;
+        self.check_fileid(dst, fileid)?;
+        for (fileid, _, _) in files.iter() {
+            self.check_fileid(dst, *fileid)?;
+        }
+        let manifest = manifest.raw();
+        let metadata = meta::Metadata {
+            path: default_name(&dirpath),
+            size: manifest.len() as u64,
+            mode: 0o644,
+            mtime: time::SystemTime::now(),
+            xattrs: Vec::new(),
+            compression: None,
+            digest: None,
+        };
+
+        // all or nothing, as in send_files_with
+        let mut connections: Vec<(Tri, SendConnection)> = Vec::new();
+        let mut handles: Vec<SendHandle> = Vec::new();
+        let tri = Tri {
+            src: self.src,
+            dst: dst,
+            fileid: fileid,
+        };
+        let (connection, handle) = SendConnection::new(tri, &self.ih, &manifest, &metadata, mtu, self.ih.tuning.bulk_rto, options)?;
+        connections.push((tri, connection));
+        handles.push(handle);
+
+        for (fileid, filepath, name) in files {
+            let tri = Tri {
+                src: self.src,
+                dst: dst,
+                fileid: fileid,
+            };
+            let filepath = filepath.to_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "non utf-8 file name")
+            })?;
+            let (connection, handle) = SendConnection::from_file(tri, &self.ih, filepath, &name, mtu, self.ih.tuning.bulk_rto, options)?;
+            connections.push((tri, connection));
+            handles.push(handle);
+        }
+        self.ih.send_manager.lock().unwrap().connections.extend(connections);
+        Ok(handles)
+    }
+
+    // sends every fragment once to `group` and repairs losses reported by `members` with Nacks
+    #[allow(dead_code)]
+    pub fn send_multicast(&mut self, fileid: u16, group: MacAddr, members: Vec<MacAddr>, filepath: String, mtu: usize, options: &SendOptions) -> io::Result<SendHandle> {
+        if !group.is_multicast() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a multicast address"));
+        }
+        if members.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty receiver group"));
+        }
+        self.check_mtu(mtu)?;
+        self.check_fileid(group, fileid)?;
+        let mut cm = self.ih.send_manager.lock().unwrap();
+        let tri = Tri {
+            src: self.src,
+            dst: group,
+            fileid: fileid,
+        };
+        let (mut connection, handle) = SendConnection::from_file(tri, &self.ih, &filepath, &default_name(&filepath), mtu, self.ih.tuning.bulk_rto, options)?;
+        connection.multicast = Some(multicast::SendGroup::new(&members));
+        cm.connections.insert(tri, connection);
+        Ok(handle)
+    }
+}
+
+fn default_name(filepath: &str) -> String {
+    Path::new(filepath)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| filepath.to_string())
+}
+
+pub struct SendHandle {
+    tri: Tri,
+    ih: InterfaceSendModeHandle,
+    done: mpsc::Receiver<io::Result<()>>,
+}
+
+impl SendHandle {
+    // blocks until the receiver has acknowledged the Fin or the transfer was reset
+    #[allow(dead_code)]
+    pub fn wait(&self) -> io::Result<()> {
+        self.done.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "connection was already closed"))
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn abort(&self) -> io::Result<()> {
+        if !self.ih.send_manager.lock().unwrap().cancel(&self.tri) {
+            return Err(io::Error::new(io::ErrorKind::Other, "connection was already closed"));
+        }
+        Ok(())
+    }
+
+    // aborts the send from another thread, while this handle waits
+    #[allow(dead_code)]
+    pub fn cancel_token(&self) -> CancelToken {
+        CancelToken { target: Cancel::Send(self.ih.clone(), self.tri) }
+    }
+}
+
+// Stops transfers from any thread. The peer is sent a Reset and the buffers are freed
+// at once; waiting SendHandles and RecvStreams fail with ConnectionAborted. Transfers
+// that already ended are left alone.
+#[derive(Clone)]
+pub struct CancelToken {
+    target: Cancel,
+}
+
+#[derive(Clone)]
+enum Cancel {
+    Send(InterfaceSendModeHandle, Tri),
+    Destination(InterfaceSendModeHandle, MacAddr), // every send to it, see InterfaceSendMode::cancel_token
+    Recv(InterfaceRecvModeHandle, Tri, u64), // and the generation of the stream
+}
+
+impl CancelToken {
+    // returns the number of transfers cancelled
+    #[allow(dead_code)]
+    pub fn cancel(&self) -> usize {
+        match &self.target {
+            Cancel::Send(ih, tri) => ih.send_manager.lock().unwrap().cancel(tri) as usize,
+            Cancel::Destination(ih, dst) => {
+                let mut cm = ih.send_manager.lock().unwrap();
+                let tris: Vec<Tri> = cm.connections.keys().filter(|tri| tri.dst == *dst).cloned().collect();
+                tris.iter().filter(|tri| cm.cancel(tri)).count()
+            },
+            Cancel::Recv(ih, tri, generation) => {
+                let cancelled = ih.recv_manager.lock().unwrap().cancel(tri, *generation);
+                ih.rcv_cv.notify_all();
+                cancelled as usize
+            },
+        }
+    }
+}
+
+// sends and receives through a single channel, see Interface::bind_peer
+#[derive(Clone)]
+pub struct InterfacePeer {
+    send: InterfaceSendMode,
+    recv: InterfaceRecvMode,
+}
+
+impl InterfacePeer {
+    #[allow(dead_code)]
+    pub fn sender(&self) -> InterfaceSendMode {
+        self.send.clone()
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver(&self) -> InterfaceRecvMode {
+        self.recv.clone()
+    }
+
+    // stops both loops, which share their workers
+    #[allow(dead_code)]
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.send.shutdown()
+    }
+
+    // answers Requests, Lists and Stats for the files under `root` for as long as it runs, which
+    // is until the interface shuts down unless the arguments are invalid. clients get a Reset for
+    // paths that cannot be sent, or that leave `root` through a symlink
+    #[allow(dead_code)]
+    pub fn serve(&self, root: String, mtu: usize, options: &SendOptions) -> io::Result<()> {
+        self.send.check_mtu(mtu)?;
+        let root = fs::canonicalize(root)?;
+        let mut sender = self.sender();
+        self.recv.ih.recv_manager.lock().unwrap().serving = true;
+        loop {
+            let (client, fileid, packet_type, path) = {
+                let mut cm = self.recv.ih.recv_manager.lock().unwrap();
+                while cm.requests.is_empty() {
+                    if self.recv.ih.stopped.load(Ordering::Relaxed) {
+                        return Err(self.recv.ih.stop.reason());
+                    }
+                    cm = self.recv.ih.rcv_cv.wait(cm).unwrap();
+                }
+                cm.requests.pop_front().unwrap()
+            };
+            let tri = Tri {
+                src: self.send.src,
+                dst: client,
+                fileid: fileid,
+            };
+            // retransmitted Requests of a file on its way
+            if self.send.ih.send_manager.lock().unwrap().connections.contains_key(&tri) {
+                continue;
+            }
+            let sent = if packet_type == packet::EftType::Request as u8 {
+                meta::served_path(&root, &path).and_then(|filepath| {
+                    filepath.to_str().map(|filepath| filepath.to_string()).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "non utf-8 file name")
+                    })
+                }).and_then(|filepath| sender.send_with(fileid, client, filepath, mtu, options))
+            } else {
+                let answer = meta::served_path(&root, &path).and_then(|target| {
+                    if packet_type == packet::EftType::List as u8 {
+                        remote::list(&target)
+                    } else {
+                        remote::Stat::from_path(&target, &default_name(&path)).map(|stat| stat.raw())
+                    }
+                });
+                answer.and_then(|data| sender.send_bytes(fileid, client, &data, &default_name(&path), mtu, options))
+            };
+            if sent.is_err() {
+                if let Some(keyring) = self.send.ih.crypto.as_ref() { // no transfer originated one
+                    keyring.originate(client, fileid);
+                }
+                self.send.ih.send_manager.lock().unwrap().resets.push(tri);
+            }
         }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    Pnet,
+    #[cfg(target_os = "linux")]
+    Ring, // memory mapped TPACKET_V3 rings, see ring.rs
+}
+
+#[derive(Clone)]
+pub struct BindOptions {
+    pub crypto: Option<crypto::CryptoConfig>, // both ends must share the key
+    pub ethertype: u16,
+    pub vlan: Option<framing::Vlan>, // tag emitted on every frame
+    pub backend: Backend,
+    pub tuning: config::Tuning,
+}
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        Self {
+            crypto: None,
+            ethertype: general::ETHERTYPE,
+            vlan: None,
+            backend: Backend::Pnet,
+            tuning: config::Tuning::default(),
+        }
+    }
+}
+
+pub struct Interface {}
+
+impl Interface {
+    #[allow(dead_code)]
+    pub fn bind_sendmode(interface_name: &str) -> io::Result<InterfaceSendMode> {
+        Self::bind_sendmode_with(interface_name, &BindOptions::default())
+    }
+
+    pub fn bind_sendmode_with(interface_name: &str, options: &BindOptions) -> io::Result<InterfaceSendMode> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == *interface_name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get interface"))?;
+
+        let src = interface.mac.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get mac addr"))?;
+        let framing = framing::Framing::new(options.ethertype, options.vlan)?;
+        options.tuning.validate()?;
+
+        // wake up periodically so that the rack loop notices a shutdown
+        let config = datalink::Config {
+            read_timeout: Some(time::Duration::from_millis(general::RECV_POLL_INTERVAL)),
+            write_buffer_size: general::FRAME_BUFFER_LENGTH * general::MAX_BATCH,
+            read_buffer_size: general::FRAME_BUFFER_LENGTH,
+            ..Default::default()
+        };
+        let (tx, rx) = channel(&interface, config, options.backend)?;
+
+        let crypto = options.crypto.as_ref().map(|config| Arc::new(crypto::Keyring::new(config)));
+        let workers = Workers::new();
+        let ih: InterfaceSendModeHandle = Arc::new(InternalInterfaceSendModeHandle {
+            send_manager: Mutex::default(),
+            probe_cv: Condvar::new(),
+            crypto: crypto.clone(),
+            tuning: options.tuning,
+            stop: workers.stop.clone(),
+            stopped: AtomicBool::new(false),
+        });
+        let (mpsc_tx, mpsc_rx) = mpsc::channel();
+        {
+            let crypto = crypto.clone();
+            let stop = workers.stop.clone();
+            workers.spawn("rack loop", move || packet_rack_loop(rx, mpsc_tx, framing, crypto, stop));
+        }
+        {
+            let ih = ih.clone();
+            let link = Link { tx: tx, framing: framing, crypto: crypto, queue: Vec::new() };
+            let announcer = discovery::Announcer::new(src, discovery::Role::Sender, capabilities(options));
+            workers.spawn("send loop", move || packet_send_loop(link, ih, mpsc_rx, Some(announcer)));
+        }
+
+        Ok(InterfaceSendMode {
+            ih: ih,
+            src: src,
+            mtu: interface_mtu(interface_name),
+            recv: None,
+            workers: Arc::new(workers),
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn bind_recvmode(interface_name: &str) -> io::Result<InterfaceRecvMode> {
+        Self::bind_recvmode_with(interface_name, &BindOptions::default())
+    }
+
+    pub fn bind_recvmode_with(interface_name: &str, options: &BindOptions) -> io::Result<InterfaceRecvMode> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == *interface_name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get interface"))?;
+
+        let dst = interface.mac.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get mac addr"))?;
+        let framing = framing::Framing::new(options.ethertype, options.vlan)?;
+        options.tuning.validate()?;
+
+        // wake up periodically so that resets queued by RecvStream::abort are flushed
+        // and a shutdown is noticed
+        let config = datalink::Config {
+            read_timeout: Some(time::Duration::from_millis(general::RECV_POLL_INTERVAL)),
+            write_buffer_size: general::FRAME_BUFFER_LENGTH,
+            read_buffer_size: general::FRAME_BUFFER_LENGTH,
+            ..Default::default()
+        };
+        let (tx, rx) = channel(&interface, config, options.backend)?;
+
+        let crypto = options.crypto.as_ref().map(|config| Arc::new(crypto::Keyring::new(config)));
+        let workers = Workers::new();
+        let ih: InterfaceRecvModeHandle = Arc::new(InternalInterfaceRecvModeHandle {
+            crypto: crypto.clone(),
+            tuning: options.tuning,
+            stop: workers.stop.clone(),
+            ..Default::default()
+        });
+
+        {
+            let ih = ih.clone();
+            let link = Link {
+                tx: tx,
+                framing: framing,
+                crypto: crypto,
+                queue: Vec::new(),
+            };
+            let announcer = discovery::Announcer::new(dst, discovery::Role::Receiver, capabilities(options));
+            workers.spawn("receive loop", move || packet_recv_loop(link, rx, ih, dst, None, announcer));
+        }
+
+        Ok(InterfaceRecvMode {
+            ih: ih,
+            dst: dst,
+            send: None,
+            workers: Arc::new(workers),
+        })
+    }
+
+    // broadcasts a Solicit and collects the Announces heard within `timeout`,
+    // one entry per station and role
+    #[allow(dead_code)]
+    pub fn discover(interface_name: &str, options: &BindOptions, timeout: time::Duration) -> io::Result<Vec<discovery::Peer>> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == *interface_name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get interface"))?;
+
+        let mac = interface.mac.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get mac addr"))?;
+        let framing = framing::Framing::new(options.ethertype, options.vlan)?;
+
+        let config = datalink::Config {
+            read_timeout: Some(time::Duration::from_millis(general::RECV_POLL_INTERVAL)),
+            write_buffer_size: general::FRAME_BUFFER_LENGTH,
+            read_buffer_size: general::FRAME_BUFFER_LENGTH,
+            ..Default::default()
+        };
+        let (tx, mut rx) = channel(&interface, config, options.backend)?;
+        let mut link = Link {
+            tx: tx,
+            framing: framing,
+            crypto: options.crypto.as_ref().map(|config| Arc::new(crypto::Keyring::new(config))),
+            queue: Vec::new(),
+        };
+        if let Some(keyring) = link.crypto.as_ref() {
+            keyring.originate(discovery::BROADCAST, general::SOLICIT_FILEID);
+        }
+
+        let start = time::Instant::now();
+        let mut solicits = 0;
+        let mut peers: Vec<discovery::Peer> = Vec::new();
+        while start.elapsed() < timeout {
+            // once more halfway through in case the first one was lost
+            if solicits == 0 || (solicits == 1 && start.elapsed() > timeout / 2) {
+                send_control(&mut link, mac, discovery::BROADCAST, packet::EftType::Solicit, general::SOLICIT_FILEID, 0)?;
+                solicits += 1;
+            }
+            let frame = match rx.next() {
+                Ok(frame) => EthernetPacket::new(frame).unwrap(),
+                Err(e) if is_fatal(&e) => return Err(e),
+                Err(_) => continue,
+            };
+            if frame.get_source() == mac {
+                continue;
+            }
+            let packet = if let Some(p) = link.framing.payload(&frame)
+                .and_then(|p| packet::EftPacket::from_raw(p).ok())
+                .filter(|p| p.header.packet_type == packet::EftType::Announce as u8)
+                .and_then(|p| authenticate(&link.crypto, &frame, p)) {
+                p
+            } else {
+                continue
+            };
+            if let Ok(peer) = discovery::Peer::from_raw(frame.get_source(), &packet.payload) {
+                match peers.iter_mut().find(|p| p.mac == peer.mac && p.role == peer.role) {
+                    Some(p) => *p = peer,
+                    None => peers.push(peer),
+                }
+            }
+        }
+        Ok(peers)
+    }
+
+    #[allow(dead_code)]
+    pub fn bind_peer(interface_name: &str) -> io::Result<InterfacePeer> {
+        Self::bind_peer_with(interface_name, &BindOptions::default())
+    }
+
+    // one channel for both directions: the receive loop hands replies to our own
+    // transfers over to the send loop, and both loops share the sender
+    #[allow(dead_code)]
+    pub fn bind_peer_with(interface_name: &str, options: &BindOptions) -> io::Result<InterfacePeer> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == *interface_name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get interface"))?;
+
+        let mac = interface.mac.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get mac addr"))?;
+        let framing = framing::Framing::new(options.ethertype, options.vlan)?;
+        options.tuning.validate()?;
+
+        let config = datalink::Config {
+            read_timeout: Some(time::Duration::from_millis(general::RECV_POLL_INTERVAL)),
+            write_buffer_size: general::FRAME_BUFFER_LENGTH * general::MAX_BATCH,
+            read_buffer_size: general::FRAME_BUFFER_LENGTH,
+            ..Default::default()
+        };
+        let (tx, rx) = channel(&interface, config, options.backend)?;
+        let tx = Arc::new(Mutex::new(tx));
+
+        // each side keeps its own sessions, as with separate channels
+        let crypto = options.crypto.as_ref().map(|config| Arc::new(crypto::Keyring::new(config)));
+        let workers = Workers::new();
+        let send_ih: InterfaceSendModeHandle = Arc::new(InternalInterfaceSendModeHandle {
+            send_manager: Mutex::default(),
+            probe_cv: Condvar::new(),
+            crypto: crypto.clone(),
+            tuning: options.tuning,
+            stop: workers.stop.clone(),
+            stopped: AtomicBool::new(false),
+        });
+        let recv_crypto = options.crypto.as_ref().map(|config| Arc::new(crypto::Keyring::new(config)));
+        let recv_ih: InterfaceRecvModeHandle = Arc::new(InternalInterfaceRecvModeHandle {
+            crypto: recv_crypto.clone(),
+            tuning: options.tuning,
+            stop: workers.stop.clone(),
+            ..Default::default()
+        });
+        let (mpsc_tx, mpsc_rx) = mpsc::channel();
+        {
+            let ih = send_ih.clone();
+            let link = Link { tx: Box::new(SharedSender(tx.clone())), framing: framing, crypto: crypto, queue: Vec::new() };
+            workers.spawn("send loop", move || packet_send_loop(link, ih, mpsc_rx, None));
+        }
+        {
+            let ih = recv_ih.clone();
+            let link = Link {
+                tx: Box::new(SharedSender(tx)),
+                framing: framing,
+                crypto: recv_crypto,
+                queue: Vec::new(),
+            };
+            let replies = Replies { tx: mpsc_tx, ih: send_ih.clone() };
+            let announcer = discovery::Announcer::new(mac, discovery::Role::Bidirectional, capabilities(options));
+            workers.spawn("receive loop", move || packet_recv_loop(link, rx, ih, mac, Some(replies), announcer));
+        }
+
+        let workers = Arc::new(workers);
+        Ok(InterfacePeer {
+            send: InterfaceSendMode {
+                ih: send_ih.clone(),
+                src: mac,
+                mtu: interface_mtu(interface_name),
+                recv: Some(recv_ih.clone()),
+                workers: workers.clone(),
+            },
+            recv: InterfaceRecvMode {
+                ih: recv_ih,
+                dst: mac,
+                send: Some(send_ih),
+                workers: workers,
+            },
+        })
+    }
+}
+
+// The threads behind a bound interface, shared by its handles. Dropping the last handle
+// or calling shutdown stops the loops: they reset the transfers still running, confirm
+// the files already read with Fin, flush and close the channel on their way out.
+// A loop that hits a fatal error of the channel stops the others the same way.
+struct Workers {
+    stop: Arc<Stop>,
+    threads: Mutex<Vec<(&'static str, thread::JoinHandle<io::Result<()>>)>>,
+}
+
+impl Workers {
+    fn new() -> Self {
+        Self {
+            stop: Arc::default(),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn spawn<F: FnOnce() -> io::Result<()> + Send + 'static>(&self, name: &'static str, f: F) {
+        self.threads.lock().unwrap().push((name, thread::spawn(f)));
+    }
+
+    // joins every loop and returns the first error one of them ended with.
+    // later calls return at once
+    fn shutdown(&self) -> io::Result<()> {
+        self.stop.raise();
+        let mut result = Ok(());
+        for (name, thread) in self.threads.lock().unwrap().drain(..) {
+            let ended = thread.join().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "panicked"))
+            });
+            if let Err(e) = ended {
+                result = result.and(Err(io::Error::new(e.kind(), format!("{}: {}", name, e))));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+    }
+}
+
+// tells the loops of an interface to stop, and why
+#[derive(Default)]
+struct Stop {
+    raised: AtomicBool,
+    error: Mutex<Option<io::Error>>, // the fatal one a loop stopped on
+}
+
+impl Stop {
+    fn raised(&self) -> bool {
+        self.raised.load(Ordering::Relaxed)
+    }
+
+    fn raise(&self) {
+        self.raised.store(true, Ordering::Relaxed);
+    }
+
+    // keeps the first error for reason
+    fn fail(&self, e: &io::Error) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(io::Error::new(e.kind(), e.to_string()));
+        }
+        self.raise();
+    }
+
+    // what transfers cut short by the stop, and calls made after it, fail with
+    fn reason(&self) -> io::Error {
+        match self.error.lock().unwrap().as_ref() {
+            Some(e) => io::Error::new(e.kind(), Stopped(format!("interface failed: {}", e))),
+            None => io::Error::new(io::ErrorKind::Other, Stopped(String::from("interface was shut down"))),
+        }
+    }
+}
+
+// the error of Stop::reason, recognized by is_fatal
+#[derive(Debug)]
+struct Stopped(String);
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for Stopped {}
+
+// whether the channel is of no more use after `e`: the interface went down or away,
+// or was shut down. anything else, a full socket buffer or a packet without a
+// session, costs a packet that goes out again later
+pub fn is_fatal(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENETDOWN) | Some(libc::ENODEV) | Some(libc::ENXIO) | Some(libc::EBADF))
+        || e.get_ref().map_or(false, |inner| inner.is::<Stopped>())
+}
+
+// passes on fatal errors only, see is_fatal
+fn fatal(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if is_fatal(&e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// binds with the settings of a config::Config; the interface given here wins over the config's
+pub struct InterfaceBuilder {
+    interface: Option<String>,
+    config: config::Config,
+}
+
+impl Interface {
+    #[allow(dead_code)]
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder {
+            interface: None,
+            config: config::Config::default(),
+        }
+    }
+}
+
+impl InterfaceBuilder {
+    #[allow(dead_code)]
+    pub fn config(mut self, config: &config::Config) -> Self {
+        self.config = config.clone();
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn interface(mut self, interface_name: &str) -> Self {
+        self.interface = Some(interface_name.to_string());
+        self
+    }
 
-        cm.connections.insert(
-            tri, 
-            SendConnection {
-                tri: tri,
-                buffer: buffer,
-                flag4buffer: utils::Flags::new(),
-                timers: Timers { send_timers: send_timers, rto: 5, },
-                cnt: 0,
-            }
-        );
-        Ok(())
+    #[allow(dead_code)]
+    pub fn bind_sendmode(self) -> io::Result<InterfaceSendMode> {
+        let (interface_name, options) = self.options()?;
+        let interface = Interface::bind_sendmode_with(&interface_name, &options)?;
+        self.limit(&interface)?;
+        Ok(interface)
     }
 
     #[allow(dead_code)]
-    pub fn send_files(&mut self, fileids: Vec<u16>, dst: MacAddr, filepaths: Vec<String>, mtu: usize) -> io::Result<()> {
-        let mut cm = self.ih.send_manager.lock().unwrap();
-        for i in 0..fileids.len() {
-            let tri = Tri {
-                src: self.src,
-                dst: dst,
-                fileid: fileids[i],
-            };
-            let data_fragments = utils::split_file(&filepaths[i], mtu)?;
-            let mut buffer: Vec<packet::EftPacket> = Vec::new();
-            let mut send_timers: Vec<time::Instant> = Vec::new();
-            let timer_init = time::Instant::now() - time::Duration::new(5, 0);
-            for (offset, data_fragment) in data_fragments.iter().enumerate() {
-                let packet = packet::EftPacket {
-                    header: packet::EftPacketHeader {
-                        packet_type:
-                            if data_fragments.len() - 1 == offset {
-                                packet::EftType::DataEnd as u8
-                            } else {
-                                packet::EftType::Data as u8
-                            },
-                        length: 8,
-                        total_length: data_fragment.len() as u16 + 8,
-                        id: tri.fileid,
-                        offset: offset as u16,
-                    },
-                    payload: data_fragment.to_vec(),
-                };
-                buffer.push(packet);
-                send_timers.push(timer_init);
-            }
+    pub fn bind_recvmode(self) -> io::Result<InterfaceRecvMode> {
+        let (interface_name, options) = self.options()?;
+        Interface::bind_recvmode_with(&interface_name, &options)
+    }
 
-            cm.connections.insert(
-                tri, 
-                SendConnection {
-                    tri: tri,
-                    buffer: buffer,
-                    flag4buffer: utils::Flags::new(),
-                    timers: Timers { send_timers: send_timers, rto: 20, },
-                    cnt: 0,
-                }
-            );
+    #[allow(dead_code)]
+    pub fn bind_peer(self) -> io::Result<InterfacePeer> {
+        let (interface_name, options) = self.options()?;
+        let peer = Interface::bind_peer_with(&interface_name, &options)?;
+        self.limit(&peer.sender())?;
+        Ok(peer)
+    }
+
+    fn options(&self) -> io::Result<(String, BindOptions)> {
+        self.config.validate()?;
+        let interface_name = self.interface.as_ref().or(self.config.interface.as_ref()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no interface configured")
+        })?;
+        Ok((interface_name.clone(), self.config.bind_options()?))
+    }
+
+    fn limit(&self, interface: &InterfaceSendMode) -> io::Result<()> {
+        let (limit, peers) = self.config.rate_limits()?;
+        interface.set_rate_limit(limit);
+        for (dst, limit) in peers {
+            interface.set_peer_rate_limit(dst, Some(limit));
         }
         Ok(())
     }
 }
 
-pub struct Interface {}
+fn channel(interface: &datalink::NetworkInterface, config: datalink::Config, backend: Backend) -> io::Result<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)> {
+    match backend {
+        Backend::Pnet => match datalink::channel(interface, config) {
+            Ok(Ethernet(tx, rx)) => Ok((tx, rx)),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "failed to create channel")),
+        },
+        #[cfg(target_os = "linux")]
+        Backend::Ring => ring::channel(interface, config.read_timeout),
+    }
+}
 
-impl Interface {
-    pub fn bind_sendmode(interface_name: &str) -> io::Result<InterfaceSendMode> {
-        let interface = datalink::interfaces()
-            .into_iter()
-            .find(|iface| iface.name == *interface_name)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get interface"))?;
+// what the Announce of an interface bound with `options` advertises
+fn capabilities(options: &BindOptions) -> u16 {
+    let mut capabilities = 0;
+    if options.crypto.is_some() {
+        capabilities |= discovery::CRYPTO;
+    }
+    if cfg!(feature = "compression") {
+        capabilities |= discovery::COMPRESSION;
+    }
+    capabilities
+}
 
-        let src = interface.mac.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get mac addr"))?;
+// the mtu the kernel reports for the interface
+fn interface_mtu(interface_name: &str) -> usize {
+    fs::read_to_string(format!("/sys/class/net/{}/mtu", interface_name))
+        .ok()
+        .and_then(|mtu| mtu.trim().parse::<usize>().ok())
+        .map_or(general::DEFAULT_MTU, |mtu| mtu.min(general::MAX_MTU))
+}
 
-        let (tx, rx) = if let Ok(Ethernet(tx, rx)) = datalink::channel(&interface, Default::default()) {
-            (tx, rx)
-        } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "failed to create channel"));
+#[derive(Default)]
+struct SendConnectionManager {
+    connections: HashMap<Tri, SendConnection>,
+    resets: Vec<Tri>,
+    probes: Vec<(EndPoint, usize)>, // path mtu probes to send
+    probed: HashMap<MacAddr, usize>, // largest probe echoed by each peer
+    limits: pacing::Limits,
+    progress: Option<mpsc::Sender<Progress>>,
+}
+
+impl SendConnectionManager {
+    // false if the connection was already closed
+    fn cancel(&mut self, tri: &Tri) -> bool {
+        let c = match self.connections.remove(tri) {
+            Some(c) => c,
+            None => return false,
         };
+        c.finish(self.progress.as_ref(), Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection aborted")));
+        self.resets.push(*tri);
+        true
+    }
 
-        let ih: InterfaceSendModeHandle = Arc::default();
-        let (mpsc_tx, mpsc_rx) = mpsc::channel();
-        {
-            thread::spawn(move || packet_rack_loop(rx, mpsc_tx));
+    // `tri` is built from a reply: dst is the replying station, which for
+    // multicast connections is a group member rather than the group address
+    fn lookup(&self, tri: &Tri) -> Option<Tri> {
+        if self.connections.contains_key(tri) {
+            return Some(*tri);
         }
-        {
-            let ih = ih.clone();
-            thread::spawn(move || packet_send_loop(tx, ih.clone(), mpsc_rx));
+        // unicast replies are addressed to us, Nacks to the group
+        self.connections.values()
+            .filter(|c| c.tri.fileid == tri.fileid && (c.tri.src == tri.src || c.tri.dst == tri.src))
+            .find(|c| c.multicast.as_ref().map_or(false, |g| g.contains(tri.dst)))
+            .map(|c| c.tri)
+    }
+}
+
+#[derive(Default)]
+struct RecvConnectionManager {
+    connections: HashMap<Tri, RecvConnection>,
+    resets: Vec<Tri>,
+    serving: bool, // Requests are queued for InterfacePeer::serve
+    requests: VecDeque<(MacAddr, u16, u8, String)>, // (client, fileid, packet type, path)
+    progress: Option<mpsc::Sender<Progress>>,
+    cancelled: HashSet<u64>, // generations, until their RecvStream is dropped, which frees the fileid
+    generation: u64, // of the last stream opened
+}
+
+impl RecvConnectionManager {
+    // a retransmission, or a new request of the client on the same fileid, replaces
+    // the one still queued
+    fn queue_request(&mut self, client: MacAddr, fileid: u16, packet_type: u8, path: String) {
+        if let Some(r) = self.requests.iter_mut().find(|r| r.0 == client && r.1 == fileid) {
+            *r = (client, fileid, packet_type, path);
+        } else if self.requests.len() < general::MAX_PENDING_REQUESTS {
+            self.requests.push_back((client, fileid, packet_type, path));
         }
+    }
 
-        Ok(InterfaceSendMode {
-            ih: ih,
-            src: src,
-        })
+    // numbers the connection of a new stream, so that the handles of an earlier
+    // stream on the same fileid cannot reach it
+    fn open(&mut self, tri: Tri, mut connection: RecvConnection) -> u64 {
+        self.generation += 1;
+        connection.generation = self.generation;
+        self.connections.insert(tri, connection);
+        self.generation
     }
 
-    pub fn bind_recvmode(interface_name: &str) -> io::Result<InterfaceRecvMode> {
-        let interface = datalink::interfaces()
-            .into_iter()
-            .find(|iface| iface.name == *interface_name)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get interface"))?;
+    // the connection of the stream numbered `generation`, if it is still there
+    fn stream(&mut self, tri: &Tri, generation: u64) -> Option<&mut RecvConnection> {
+        self.connections.get_mut(tri).filter(|c| c.generation == generation)
+    }
 
-        let dst = interface.mac.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to get mac addr"))?;
+    // false if the stream was already read or closed
+    fn cancel(&mut self, tri: &Tri, generation: u64) -> bool {
+        if self.stream(tri, generation).map_or(true, |c| c.closed) {
+            return false;
+        }
+        let mut c = self.connections.remove(tri).unwrap();
+        if !c.reset {
+            self.resets.push(*tri);
+        }
+        if let Some(progress) = self.progress.as_ref() {
+            c.reset = true;
+            c.report(tri, progress);
+        }
+        if let Some(sidecar) = c.sidecar.take() {
+            sidecar.remove().ok();
+        }
+        self.cancelled.insert(generation);
+        true
+    }
+}
 
-        let (tx, rx) = if let Ok(Ethernet(tx, rx)) = datalink::channel(&interface, Default::default()) {
-            (tx, rx)
-        } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "failed to create channel"));
-        };
+// every packet leaves the interface through here, sealed when a pre-shared key is configured
+struct Link {
+    tx: Box<dyn DataLinkSender + 'static>,
+    framing: framing::Framing,
+    crypto: Option<Arc<crypto::Keyring>>,
+    queue: Vec<(MacAddr, MacAddr, Vec<u8>)>, // sealed packets waiting for flush
+}
 
-        let ih: InterfaceRecvModeHandle = Arc::default();
+impl Link {
+    fn send(&mut self, src_address: MacAddr, dst_address: MacAddr, packet: &packet::EftPacket) -> io::Result<()> {
+        let packet = self.seal(src_address, dst_address, packet)?;
+        let framing = self.framing;
+        self.tx.build_and_send(1, framing.frame_length(packet.len()),
+            &mut |new_packet| framing.build(new_packet, src_address, dst_address, &packet)
+        ).ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to send packet"))?
+    }
 
-        {
-            let ih = ih.clone();
-            thread::spawn(move || packet_recv_loop(tx, rx, ih.clone()));
+    fn queue(&mut self, src_address: MacAddr, dst_address: MacAddr, packet: &packet::EftPacket) -> io::Result<()> {
+        let packet = self.seal(src_address, dst_address, packet)?;
+        self.queue.push((src_address, dst_address, packet));
+        Ok(())
+    }
+
+    // sends the queue in order, handing runs of equally long frames to the datalink at once
+    fn flush(&mut self) -> io::Result<()> {
+        let framing = self.framing;
+        let queue = mem::replace(&mut self.queue, Vec::new());
+        let mut result = Ok(());
+        let mut start = 0;
+        while start < queue.len() {
+            let length = queue[start].2.len();
+            let mut end = start + 1;
+            while end < queue.len() && end - start < general::MAX_BATCH && queue[end].2.len() == length {
+                end += 1;
+            }
+            let mut batch = queue[start..end].iter();
+            let sent = self.tx.build_and_send(end - start, framing.frame_length(length),
+                &mut |new_packet| {
+                    if let Some((src_address, dst_address, packet)) = batch.next() {
+                        framing.build(new_packet, *src_address, *dst_address, packet);
+                    }
+                }
+            ).unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::Other, "failed to send packet")));
+            if result.is_ok() {
+                result = sent;
+            }
+            start = end;
         }
+        result
+    }
 
-        Ok(InterfaceRecvMode {
-            ih: ih,
-            dst: dst,
-        })
+    // bytes on the wire for an EFT packet of `length` bytes
+    fn wire_length(&self, length: usize) -> usize {
+        match self.crypto {
+            Some(_) => self.framing.frame_length(length + general::AEAD_OVERHEAD),
+            None => self.framing.frame_length(length),
+        }
+    }
+
+    fn seal(&self, src_address: MacAddr, dst_address: MacAddr, packet: &packet::EftPacket) -> io::Result<Vec<u8>> {
+        match self.crypto.as_ref() {
+            Some(keyring) => Ok(keyring.seal(src_address, dst_address, packet)?.raw()),
+            None => Ok(packet.raw()),
+        }
     }
 }
 
-#[derive(Default)]
-struct SendConnectionManager {
-    connections: HashMap<Tri, SendConnection>,
+// lets the loops of an InterfacePeer send through the same channel
+struct SharedSender(Arc<Mutex<Box<dyn DataLinkSender>>>);
+
+impl DataLinkSender for SharedSender {
+    fn build_and_send(&mut self, num_packets: usize, packet_size: usize, func: &mut dyn FnMut(&mut [u8])) -> Option<io::Result<()>> {
+        self.0.lock().unwrap().build_and_send(num_packets, packet_size, func)
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: Option<datalink::NetworkInterface>) -> Option<io::Result<()>> {
+        self.0.lock().unwrap().send_to(packet, dst)
+    }
 }
 
-#[derive(Default)]
-struct RecvConnectionManager {
-    connections: HashMap<Tri, RecvConnection>,
+// drops frames that fail authentication, and passes everything through without a key
+fn authenticate(crypto: &Option<Arc<crypto::Keyring>>, frame: &EthernetPacket, packet: packet::EftPacket) -> Option<packet::EftPacket> {
+    match crypto {
+        Some(keyring) => keyring.open(frame.get_source(), frame.get_destination(), &packet).ok(),
+        None => Some(packet),
+    }
+}
+
+// broadcasts our Announce when one is due
+fn announce(link: &mut Link, announcer: &mut discovery::Announcer) -> io::Result<()> {
+    if let Some(payload) = announcer.due() {
+        if let Some(keyring) = link.crypto.as_ref() {
+            keyring.originate(discovery::BROADCAST, general::ANNOUNCE_FILEID);
+        }
+        send_packet(link, announcer.mac(), discovery::BROADCAST, packet::EftType::Announce, general::ANNOUNCE_FILEID, 0, payload)?;
+    }
+    Ok(())
+}
+
+fn send_control(link: &mut Link, src_address: MacAddr, dst_address: MacAddr, packet_type: packet::EftType, id: u16, offset: u16) -> io::Result<()> {
+    send_packet(link, src_address, dst_address, packet_type, id, offset, vec![])
 }
 
-fn send_ack(tx: &mut Box<dyn DataLinkSender + 'static>, src_address: MacAddr, dst_address: MacAddr, id: u16, offset: u16) -> io::Result<()> {
-    let packet = packet::EftPacket {
+fn send_packet(link: &mut Link, src_address: MacAddr, dst_address: MacAddr, packet_type: packet::EftType, id: u16, offset: u16, payload: Vec<u8>) -> io::Result<()> {
+    link.send(src_address, dst_address, &eft_packet(packet_type, id, offset, payload))
+}
+
+fn eft_packet(packet_type: packet::EftType, id: u16, offset: u16, payload: Vec<u8>) -> packet::EftPacket {
+    packet::EftPacket {
         header: packet::EftPacketHeader {
-            packet_type: packet::EftType::Ack as u8,
+            packet_type: packet_type as u8,
             length: 8,
-            total_length: 8,
+            total_length: payload.len() as u16 + 8,
             id: id,
             offset: offset,
         },
-        payload: vec![],
-    };
-    
-    let packet = packet.raw();
-    tx.build_and_send(1, 14+packet.len(),
-        &mut |new_packet| {
-            let mut new_packet = MutableEthernetPacket::new(new_packet).unwrap();
-
-            new_packet.set_source(src_address);
-            new_packet.set_destination(dst_address);
-            new_packet.set_ethertype(EtherType(0xEF7));
-            new_packet.set_payload(&packet);
-        }
-    ).ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to send ack"))??;
-    
-    Ok(())
+        payload: payload,
+    }
 }
 
 struct Message {
     tri: Tri,
+    packet_type: u8,
     offset: u16,
+    payload: Vec<u8>,
 }
 
-#[allow(unused_must_use)]
-fn packet_rack_loop(mut rx: Box<dyn DataLinkReceiver + 'static>, mpsc_tx: mpsc::Sender<Message>) -> io::Result<()> {
-    loop {
+impl Message {
+    // keyed like the send connection the frame answers
+    fn reply(frame: &EthernetPacket, packet: packet::EftPacket) -> Self {
+        Message {
+            tri: Tri {
+                src: frame.get_destination(),
+                dst: frame.get_source(),
+                fileid: packet.header.id,
+            },
+            packet_type: packet.header.packet_type,
+            offset: packet.header.offset,
+            payload: packet.payload,
+        }
+    }
+}
+
+// the send side of an InterfacePeer, fed by its receive loop
+struct Replies {
+    tx: mpsc::Sender<Message>,
+    ih: InterfaceSendModeHandle,
+}
+
+impl Replies {
+    // whether the still sealed `packet` answers one of our transfers rather than belonging to one
+    // we receive. Meta and Probe are echoed without payload, Fin and Reset go where the connection is
+    fn claims(&self, frame: &EthernetPacket, packet: &packet::EftPacket) -> bool {
+        let packet_type = packet.header.packet_type;
+        if packet_type == packet::EftType::Ack as u8 || packet_type == packet::EftType::Sack as u8 {
+            return true;
+        }
+        if packet_type == packet::EftType::Meta as u8 || packet_type == packet::EftType::Probe as u8 {
+            let overhead = if self.ih.crypto.is_some() { general::AEAD_OVERHEAD } else { 0 };
+            return packet.payload.len() <= overhead;
+        }
+        if packet_type == packet::EftType::Keepalive as u8 { // probes from a receiver, not echoes
+            return packet.header.offset == 0;
+        }
+        if packet_type == packet::EftType::Fin as u8 || packet_type == packet::EftType::Reset as u8 {
+            let tri = Tri {
+                src: frame.get_destination(),
+                dst: frame.get_source(),
+                fileid: packet.header.id,
+            };
+            return self.ih.send_manager.lock().unwrap().lookup(&tri).is_some();
+        }
+        false
+    }
+}
+
+fn packet_rack_loop(mut rx: Box<dyn DataLinkReceiver + 'static>, mpsc_tx: mpsc::Sender<Message>, framing: framing::Framing, crypto: Option<Arc<crypto::Keyring>>, stop: Arc<Stop>) -> io::Result<()> {
+    while !stop.raised() {
         match rx.next() {
             Ok(frame) => {
                 let frame = EthernetPacket::new(frame).unwrap();
-                if frame.get_ethertype() != EtherType(0xEF7) {
-                    continue;
-                }
-
-                let packet = if let Ok(p) = packet::EftPacket::from_raw(frame.payload().to_vec()) {
+                let packet = if let Some(p) = framing.payload(&frame).and_then(|p| packet::EftPacket::from_raw(p).ok()) {
                     p
                 } else {
                     continue
                 };
 
-                let t = Tri {
-                    src: frame.get_destination(),
-                    dst: frame.get_source(),
-                    fileid: packet.header.id,
+                if packet.header.packet_type != packet::EftType::Ack as u8
+                    && packet.header.packet_type != packet::EftType::Sack as u8
+                    && packet.header.packet_type != packet::EftType::Nack as u8
+                    && packet.header.packet_type != packet::EftType::Meta as u8
+                    && packet.header.packet_type != packet::EftType::Probe as u8
+                    && packet.header.packet_type != packet::EftType::Fin as u8
+                    && packet.header.packet_type != packet::EftType::Reset as u8
+                    && packet.header.packet_type != packet::EftType::Solicit as u8
+                    && packet.header.packet_type != packet::EftType::Keepalive as u8 {
+                    continue;
+                }
+                let packet = if let Some(p) = authenticate(&crypto, &frame, packet) {
+                    p
+                } else {
+                    continue
                 };
 
-                mpsc_tx.send(Message { tri: t, offset: packet.header.offset, });
+                if mpsc_tx.send(Message::reply(&frame, packet)).is_err() { // the send loop has exited
+                    break;
+                }
+            },
+            Err(e) if is_fatal(&e) => {
+                stop.fail(&e);
+                return Err(e);
             },
             Err(_) => continue,
         }
     }
+    Ok(())
 }
 
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
@@ -319,23 +1524,100 @@ struct EndPoint {
     dst: MacAddr,
 }
 
-#[allow(unused_must_use)]
-fn packet_send_loop(mut tx: Box<dyn DataLinkSender + 'static>, ih: InterfaceSendModeHandle, mpsc_rx: mpsc::Receiver<Message>) {
+fn packet_send_loop(mut link: Link, ih: InterfaceSendModeHandle, mpsc_rx: mpsc::Receiver<Message>, announcer: Option<discovery::Announcer>) -> io::Result<()> {
+    let result = run_sends(&mut link, &ih, mpsc_rx, announcer);
+    if let Err(e) = result.as_ref() {
+        ih.stop.fail(e);
+    }
+    let mut cm = ih.send_manager.lock().unwrap();
+    result.and(close_sends(&mut link, &ih, &mut cm))
+}
+
+// sends until the interface stops, or the channel fails
+fn run_sends(link: &mut Link, ih: &InterfaceSendModeHandle, mpsc_rx: mpsc::Receiver<Message>, mut announcer: Option<discovery::Announcer>) -> io::Result<()> {
     // let mut fast_retransmissions: HashMap<EndPoint, BTreeMap<u16, BTreeMap<u16, bool>>> = HashMap::new();
-    let mut timeout_retransmissions: HashMap<EndPoint, BTreeMap<u16, BTreeMap<u16, bool>>> = HashMap::new();
+    let mut scheduler = sched::Scheduler::default();
     loop {
         let mut cmg = ih.send_manager.lock().unwrap();
         let cm = &mut *cmg;
+        if ih.stop.raised() {
+            return Ok(());
+        }
         loop { // get fast_retransmissions
             if let Ok(m) = mpsc_rx.try_recv() {
-                let c = if let Some(c) = cm.connections.get_mut(&m.tri) {
-                    c
+                if m.packet_type == packet::EftType::Solicit as u8 {
+                    if let Some(announcer) = announcer.as_mut() {
+                        announcer.solicit();
+                    }
+                    continue;
+                }
+                if m.packet_type == packet::EftType::Probe as u8 {
+                    let probed = cm.probed.entry(m.tri.dst).or_insert(0);
+                    *probed = (*probed).max(m.offset as usize);
+                    ih.probe_cv.notify_all();
+                    continue;
+                }
+                let tri = if let Some(tri) = cm.lookup(&m.tri) {
+                    tri
+                } else {
+                    continue
+                };
+                let peer = m.tri.dst;
+                if let Some(c) = cm.connections.get_mut(&tri) {
+                    c.heard();
+                    if m.packet_type == packet::EftType::Keepalive as u8 { // echoed to the group for multicast
+                        fatal(send_control(link, tri.src, c.tri.dst, packet::EftType::Keepalive, tri.fileid, 1))?;
+                        continue;
+                    }
+                }
+                if m.packet_type == packet::EftType::Fin as u8 { // receiver released the connection
+                    if let Some(c) = cm.connections.remove(&tri) {
+                        let result = c.outcome();
+                        c.finish(cm.progress.as_ref(), result);
+                    }
+                    continue;
+                }
+                let c = cm.connections.get_mut(&tri).unwrap();
+                if let Some(group) = c.multicast.as_mut() {
+                    // a member that resets or completes leaves the group
+                    let fin = if m.packet_type == packet::EftType::Nack as u8 {
+                        if group.on_nack(peer, &m.payload).is_err() { // malformed bitmap
+                            continue;
+                        }
+                        false
+                    } else if m.packet_type == packet::EftType::Sack as u8 {
+                        group.on_complete(peer)
+                    } else if m.packet_type == packet::EftType::Reset as u8 {
+                        group.on_reset(peer)
+                    } else {
+                        false
+                    };
+                    if fin && c.fin.is_none() {
+                        c.close();
+                    }
+                    continue;
+                }
+                if m.packet_type == packet::EftType::Reset as u8 {
+                    if let Some(c) = cm.connections.remove(&tri) {
+                        c.finish(cm.progress.as_ref(), Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer")));
+                    }
+                    continue;
+                }
+                let acked = if m.packet_type == packet::EftType::Sack as u8 {
+                    c.on_sack(&m.payload)
+                } else if m.packet_type == packet::EftType::Ack as u8 {
+                    c.on_packet(m.offset)
+                } else if m.packet_type == packet::EftType::Meta as u8 {
+                    c.meta = None;
+                    Ok(false)
                 } else {
                     continue
                 };
-                if let Ok(fin) = c.on_packet(m.offset) { // if let Ok((fin, fr)) = c.on_packet(m.offset) {
-                    if fin { // file sent
-                        cm.connections.remove(&m.tri); // ConnectionManagerから削除
+                if acked.is_ok() { // if let Ok((fin, fr)) = c.on_packet(m.offset) {
+                    if c.acked_all() { // file sent
+                        if c.fin.is_none() {
+                            c.close();
+                        }
                         continue;
                     }
                     // if let Some(offsets) = fr {
@@ -353,17 +1635,62 @@ fn packet_send_loop(mut tx: Box<dyn DataLinkSender + 'static>, ih: InterfaceSend
                 break;
             }
         }
+        for tri in cm.resets.drain(..) {
+            fatal(send_control(link, tri.src, tri.dst, packet::EftType::Reset, tri.fileid, 0))?;
+        }
+        for (endpoint, size) in cm.probes.drain(..) {
+            let overhead = if link.crypto.is_some() { general::AEAD_OVERHEAD } else { 0 };
+            let padding = vec![0; size - general::EFT_HEADER_LENGTH - overhead];
+            fatal(send_packet(link, endpoint.src, endpoint.dst, packet::EftType::Probe, general::PROBE_FILEID, size as u16, padding))?;
+        }
+        if let Some(announcer) = announcer.as_mut() {
+            fatal(announce(link, announcer))?;
+        }
+        let mut released: Vec<Tri> = Vec::new();
+        let mut failed: Vec<(Tri, io::Error)> = Vec::new();
+        let progress = cm.progress.clone();
         for connection in cm.connections.values_mut() { // get timeout packets
+            if let Some(progress) = progress.as_ref() {
+                if connection.tracker.due() {
+                    progress.send(connection.progress(Status::Running)).ok();
+                }
+            }
+            if connection.fin.is_some() {
+                if connection.fin_timeout() {
+                    if connection.fin_cnt >= general::MAX_FIN_RETRIES {
+                        // every offset was acknowledged, so the receiver has the whole file
+                        released.push(connection.tri);
+                    } else {
+                        fatal(connection.write_fin(link))?;
+                    }
+                }
+                continue;
+            }
+            if let Some(e) = connection.failure() {
+                failed.push((connection.tri, e));
+                continue;
+            }
+            if connection.meta_timeout() {
+                fatal(connection.write_meta(link))?;
+            }
             for offset in connection.timeouts() {
-                timeout_retransmissions
-                    .entry(EndPoint { src: connection.tri.src, dst: connection.tri.dst, })
-                    .or_insert(BTreeMap::new())
-                    .entry(connection.tri.fileid)
-                    .or_insert(BTreeMap::new())
-                    .insert(offset, true);
+                let cost = link.wire_length(connection.buffer[offset as usize].header.total_length as usize);
+                scheduler.push(connection.tri, connection.priority, offset, cost);
+            }
+        }
+        for tri in released {
+            if let Some(c) = cm.connections.remove(&tri) {
+                let result = c.outcome();
+                c.finish(cm.progress.as_ref(), result);
             }
         }
-        
+        for (tri, e) in failed { // the receiver is told in case it is still there
+            if let Some(c) = cm.connections.remove(&tri) {
+                c.finish(cm.progress.as_ref(), Err(e));
+            }
+            cm.resets.push(tri);
+        }
+
         // let mut cnt = 0;
         // for fast_retransmission in fast_retransmissions.iter_mut() {
         //     'outer1: for (fileid, next) in fast_retransmission.1.iter_mut() {
@@ -371,7 +1698,7 @@ fn packet_send_loop(mut tx: Box<dyn DataLinkSender + 'static>, ih: InterfaceSend
         //             if *b {
         //                 let tri = Tri { src: fast_retransmission.0.src, dst: fast_retransmission.0.dst, fileid: *fileid, };
         //                 if let Some(c) = cm.connections.get_mut(&tri) {
-        //                     c.write(&mut tx, *offset);
+        //                     c.write(&mut link, *offset);
         //                     *b = false;
         //                     cnt += 1;
         //                     if cnt > 300 {
@@ -382,41 +1709,225 @@ fn packet_send_loop(mut tx: Box<dyn DataLinkSender + 'static>, ih: InterfaceSend
         //         }
         //     }
         // }
+        // forget retransmission entries of closed connections, and of those closing whose
+        // payloads are gone
+        scheduler.retain(|tri| cm.connections.get(tri).map_or(false, |c| c.fin.is_none()));
         let mut cnt = 0;
-        for timeout_retransmission in timeout_retransmissions.iter_mut() {
-            'outer2: for (fileid, next) in timeout_retransmission.1.iter_mut() {
-                for (offset, b) in next.iter_mut() {
-                    if *b {
-                        let tri = Tri { src: timeout_retransmission.0.src, dst: timeout_retransmission.0.dst, fileid: *fileid, };
-                        if let Some(c) = cm.connections.get_mut(&tri) {
-                            c.write(&mut tx, *offset);
-                            *b = false;
-                            cnt += 1;
-                            if cnt > 300 {
-                                break 'outer2;
-                            }
-                        }
-                    }
+        let mut throttled: Option<time::Duration> = None; // when fragments are due but out of tokens
+        while cnt < ih.tuning.burst {
+            let limits = &mut cm.limits;
+            let wait = &mut throttled;
+            let next = scheduler.next(|tri, cost| {
+                if limits.admit(tri.dst, cost) {
+                    return true;
                 }
+                let due = limits.wait(tri.dst);
+                *wait = Some(wait.map_or(due, |wait| wait.min(due)));
+                false
+            });
+            let (tri, offset) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            throttled = None;
+            if let Some(c) = cm.connections.get_mut(&tri) {
+                fatal(c.write(link, offset))?;
+                cnt += 1;
             }
         }
-    }
-}
+        fatal(link.flush())?;
+        if let Some(wait) = throttled {
+            drop(cmg);
+            thread::sleep(wait.min(time::Duration::from_millis(general::MAX_PACING_WAIT)));
+        }
+    }
+}
+
+// ends every send once the interface stops: those already acknowledged in full
+// with Fin, the others with Reset
+fn close_sends(link: &mut Link, ih: &InterfaceSendModeHandle, cm: &mut SendConnectionManager) -> io::Result<()> {
+    let mut result = Ok(());
+    for (tri, mut c) in cm.connections.drain() {
+        if c.fin.is_some() {
+            result = result.and(c.write_fin(link));
+            let outcome = c.outcome();
+            c.finish(cm.progress.as_ref(), outcome);
+        } else {
+            result = result.and(send_control(link, tri.src, tri.dst, packet::EftType::Reset, tri.fileid, 0));
+            c.finish(cm.progress.as_ref(), Err(ih.stop.reason()));
+        }
+    }
+    for tri in cm.resets.drain(..) {
+        result = result.and(send_control(link, tri.src, tri.dst, packet::EftType::Reset, tri.fileid, 0));
+    }
+    result = result.and(link.flush());
+    ih.stopped.store(true, Ordering::Relaxed);
+    ih.probe_cv.notify_all();
+    result
+}
+
+struct Timers {
+    send_timers: Vec<time::Instant>,
+    rto: u32,
+    backdate: time::Duration,
+}
+
+struct SendConnection {
+    tri: Tri,
+    buffer: Vec<packet::EftPacket>,
+    flag4buffer: utils::Flags,
+    timers: Timers,
+    cnt: usize,
+    fin: Option<time::Instant>,
+    fin_cnt: usize,
+    done: mpsc::Sender<io::Result<()>>,
+    fec: Option<fec::Encoder>,
+    multicast: Option<multicast::SendGroup>,
+    meta: Option<packet::EftPacket>, // until the receiver echoes it
+    meta_timer: time::Instant,
+    priority: Priority,
+    sent: utils::Flags, // offsets written at least once
+    retries: usize, // retransmissions since the receiver was last heard
+    max_retries: usize,
+    last_heard: time::Instant,
+    deadline: Option<time::Instant>,
+    tracker: progress::Tracker,
+    name: String,
+    total_bytes: u64, // as transferred
+}
+
+impl SendConnection {
+    fn from_file(tri: Tri, ih: &InterfaceSendModeHandle, filepath: &str, name: &str, mtu: usize, rto: u32, options: &SendOptions) -> io::Result<(Self, SendHandle)> {
+        let metadata = meta::Metadata::from_path(filepath, name)?;
+        Self::new(tri, ih, &fs::read(filepath)?, &metadata, mtu, rto, options)
+    }
+
+    fn new(tri: Tri, ih: &InterfaceSendModeHandle, data: &[u8], metadata: &meta::Metadata, mtu: usize, rto: u32, options: &SendOptions) -> io::Result<(Self, SendHandle)> {
+        if ih.stopped.load(Ordering::Relaxed) { // nobody would ever send it
+            return Err(ih.stop.reason());
+        }
+        let mut metadata = metadata.clone();
+        metadata.size = data.len() as u64;
+        if options.digest {
+            let mut digest = [0; 32];
+            digest.copy_from_slice(&Sha256::digest(data));
+            metadata.digest = Some(digest);
+        }
+        let compressed: Vec<u8>;
+        let data = match options.compression {
+            Some(c) if c.worthwhile(data)? => {
+                compressed = c.compress(data)?;
+                metadata.compression = Some(c);
+                &compressed[..]
+            },
+            _ => data,
+        };
+
+        let overhead = match ih.crypto.as_ref() {
+            Some(keyring) => {
+                keyring.originate(tri.dst, tri.fileid);
+                general::AEAD_OVERHEAD
+            },
+            None => 0,
+        };
+        let mtu = mtu.checked_sub(overhead).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "mtu too small")
+        })?;
+
+        let name = metadata.path.clone();
+        let total_bytes = data.len() as u64;
+        let metadata = metadata.raw()?;
+        if metadata.len() + general::EFT_HEADER_LENGTH > mtu {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "metadata too large"));
+        }
+        let meta = packet::EftPacket {
+            header: packet::EftPacketHeader {
+                packet_type: packet::EftType::Meta as u8,
+                length: 8,
+                total_length: metadata.len() as u16 + 8,
+                id: tri.fileid,
+                offset: 0,
+            },
+            payload: metadata,
+        };
+
+        let fragment_size = match options.fec {
+            // parity packets carry the fec header on top of a full fragment
+            Some(_) => mtu.checked_sub(general::FEC_HEADER_LENGTH),
+            None => Some(mtu),
+        }.filter(|size| *size > general::EFT_HEADER_LENGTH).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "mtu too small")
+        })?;
+        let data_fragments = utils::split_data(data, fragment_size);
+        if data_fragments.len() > ih.tuning.max_fragments {
+            let limit = format!("file too large: {} fragments, at most {} of {} bytes", data_fragments.len(), ih.tuning.max_fragments, fragment_size - general::EFT_HEADER_LENGTH);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, limit));
+        }
+        let fec = match options.fec {
+            Some(config) => Some(fec::Encoder::new(config, &data_fragments)?),
+            None => None,
+        };
+        let mut buffer: Vec<packet::EftPacket> = Vec::new();
+        let mut send_timers: Vec<time::Instant> = Vec::new();
+        let backdate = time::Duration::from_millis(ih.tuning.backdate);
+        let timer_init = time::Instant::now() - backdate;
+        for (offset, data_fragment) in data_fragments.iter().enumerate() {
+            let packet = packet::EftPacket {
+                header: packet::EftPacketHeader {
+                    packet_type:
+                        if data_fragments.len() - 1 == offset {
+                            packet::EftType::DataEnd as u8
+                        } else {
+                            packet::EftType::Data as u8
+                        },
+                    length: 8,
+                    total_length: data_fragment.len() as u16 + 8,
+                    id: tri.fileid,
+                    offset: offset as u16,
+                },
+                payload: data_fragment.to_vec(),
+            };
+            buffer.push(packet);
+            send_timers.push(timer_init);
+        }
 
-struct Timers {
-    send_timers: Vec<time::Instant>,
-    rto: u32,
-}
+        let mut flag4buffer = utils::Flags::new();
+        flag4buffer.set_length(buffer.len())?;
 
-struct SendConnection {
-    tri: Tri,
-    buffer: Vec<packet::EftPacket>,
-    flag4buffer: utils::Flags,
-    timers: Timers,
-    cnt: usize,
-}
+        let now = time::Instant::now();
+        let (done_tx, done_rx) = mpsc::channel();
+        Ok((
+            SendConnection {
+                tri: tri,
+                buffer: buffer,
+                flag4buffer: flag4buffer,
+                timers: Timers { send_timers: send_timers, rto: rto, backdate: backdate, },
+                cnt: 0,
+                fin: None,
+                fin_cnt: 0,
+                done: done_tx,
+                fec: fec,
+                multicast: None,
+                meta: Some(meta),
+                meta_timer: timer_init,
+                priority: options.priority,
+                sent: utils::Flags::new(),
+                retries: 0,
+                max_retries: options.max_retries.unwrap_or(general::MAX_RETRIES),
+                last_heard: now,
+                deadline: options.deadline.map(|deadline| now + deadline),
+                tracker: progress::Tracker::new(),
+                name: name,
+                total_bytes: total_bytes,
+            },
+            SendHandle {
+                tri: tri,
+                ih: ih.clone(),
+                done: done_rx,
+            }
+        ))
+    }
 
-impl SendConnection {
     fn on_packet<'a>(&mut self, offset: u16) -> io::Result<bool> { // -> io::Result<(bool, Option<Vec<u16>>)>
         if self.flag4buffer.isset(offset as usize)? {
             // return Ok((false, None));
@@ -425,6 +1936,7 @@ impl SendConnection {
         self.flag4buffer.set(offset as usize)?;
 
         self.cnt += 1;
+        self.tracker.add(self.buffer.get(offset as usize).map_or(0, |packet| packet.payload.len()));
 
         // 高速再転送
         // let mut fast_retransmissions: Vec<u16> = Vec::new();
@@ -443,7 +1955,105 @@ impl SendConnection {
         Ok(false)
     }
 
-    fn timeouts(&self) -> Vec<u16> {
+    fn on_sack(&mut self, bitmap: &[u8]) -> io::Result<bool> {
+        let held = utils::Flags::from_bytes(bitmap)?;
+        let mut fin = false;
+        for offset in 0..self.flag4buffer.get_length()? {
+            if held.isset(offset)? {
+                fin |= self.on_packet(offset as u16)?;
+            }
+        }
+        Ok(fin)
+    }
+
+    fn acked_all(&self) -> bool {
+        self.meta.is_none() && self.flag4buffer.get_length().map_or(false, |l| l == self.cnt)
+    }
+
+    // every offset is acknowledged: drop the payloads and start the close sequence
+    fn close(&mut self) {
+        self.meta = None;
+        self.buffer = Vec::new();
+        self.timers.send_timers = Vec::new();
+        self.fec = None;
+        self.fin = Some(time::Instant::now() - self.timers.backdate);
+    }
+
+    // how a send that got as far as Fin went: a multicast one fails for members that reset
+    fn outcome(&self) -> io::Result<()> {
+        match self.multicast.as_ref() {
+            Some(group) => group.outcome(),
+            None => Ok(()),
+        }
+    }
+
+    fn fin_timeout(&self) -> bool {
+        match self.fin {
+            Some(timer) => timer.elapsed().as_millis() > self.timers.rto as u128,
+            None => false,
+        }
+    }
+
+    fn heard(&mut self) {
+        self.last_heard = time::Instant::now();
+        self.retries = 0;
+    }
+
+    fn progress(&self, status: Status) -> Progress {
+        let total_fragments = self.flag4buffer.get_length().ok();
+        self.tracker.progress(self.tri.fileid, self.tri.dst, &self.name, total_fragments, Some(self.total_bytes), status)
+    }
+
+    // hands `result` to the SendHandle, reporting it first
+    fn finish(mut self, progress: Option<&mpsc::Sender<Progress>>, result: io::Result<()>) {
+        if let Some(progress) = progress {
+            if self.tracker.end() {
+                let status = if result.is_ok() { Status::Done } else { Status::Failed };
+                progress.send(self.progress(status)).ok();
+            }
+        }
+        self.done.send(result).ok(); // the handle may be gone
+    }
+
+    // the receiver stopped answering or the transfer ran out of time
+    fn failure(&self) -> Option<io::Error> {
+        if self.deadline.map_or(false, |deadline| time::Instant::now() > deadline) {
+            return Some(io::Error::new(io::ErrorKind::TimedOut, "transfer deadline exceeded"));
+        }
+        if self.retries > self.max_retries && self.last_heard.elapsed().as_millis() > general::PEER_TIMEOUT as u128 {
+            return Some(io::Error::new(io::ErrorKind::TimedOut, "peer unreachable"));
+        }
+        None
+    }
+
+    fn meta_timeout(&self) -> bool {
+        // multicast members never echo Meta, it goes out with every probe instead
+        let interval = match self.multicast {
+            Some(_) => general::MULTICAST_PROBE_INTERVAL as u128,
+            None => self.timers.rto as u128,
+        };
+        self.meta.is_some() && self.meta_timer.elapsed().as_millis() > interval
+    }
+
+    fn write_meta(&mut self, link: &mut Link) -> io::Result<()> {
+        self.retries += 1;
+        self.meta_timer = time::Instant::now();
+        if let Some(meta) = self.meta.as_ref() {
+            send_packet(link, self.tri.src, self.tri.dst, packet::EftType::Meta, self.tri.fileid, 0, meta.payload.clone())?;
+        }
+        Ok(())
+    }
+
+    fn write_fin(&mut self, link: &mut Link) -> io::Result<()> {
+        self.fin = Some(time::Instant::now());
+        self.fin_cnt += 1;
+        send_control(link, self.tri.src, self.tri.dst, packet::EftType::Fin, self.tri.fileid, self.cnt as u16)
+    }
+
+    fn timeouts(&mut self) -> Vec<u16> {
+        if let Some(group) = self.multicast.as_mut() {
+            return group.due(self.buffer.len());
+        }
         let mut timeouts: Vec<u16> = Vec::new();
         for (offset, timer) in self.timers.send_timers.iter().enumerate() {
             if timer.elapsed().as_millis() > self.timers.rto as u128 && !self.flag4buffer.isset(offset).unwrap() { // TODO
@@ -453,43 +2063,164 @@ impl SendConnection {
         timeouts
     }
 
-    fn write(&mut self, tx: &mut Box<dyn DataLinkSender + 'static>, offset: u16) -> io::Result<()> {
+    fn write(&mut self, link: &mut Link, offset: u16) -> io::Result<()> {
         if self.flag4buffer.isset(offset as usize)? {
             return Ok(())
         }
-        let packet = self.buffer[offset as usize].raw();
-        tx.build_and_send(1, 14+packet.len(),
-            &mut |new_packet| {
-                let mut new_packet = MutableEthernetPacket::new(new_packet).unwrap();
-
-                new_packet.set_source(self.tri.src);
-                new_packet.set_destination(self.tri.dst);
-                new_packet.set_ethertype(EtherType(0xEF7));
-                new_packet.set_payload(&packet);
-            }
-        );
+        link.queue(self.tri.src, self.tri.dst, &self.buffer[offset as usize])?;
         self.timers.send_timers[offset as usize] = time::Instant::now();
+        if self.sent.isset(offset as usize)? {
+            self.retries += 1;
+        } else {
+            self.sent.set(offset as usize)?;
+        }
+        if let Some(encoder) = self.fec.as_mut() {
+            for parity in encoder.parity_for(offset, self.buffer.len()) {
+                link.queue(self.tri.src, self.tri.dst, &eft_packet(packet::EftType::Parity, self.tri.fileid, offset, parity.clone()))?;
+            }
+        }
         Ok(())
     }
 }
 
-#[allow(unused_must_use)]
-fn packet_recv_loop(mut tx: Box<dyn DataLinkSender + 'static>, mut rx: Box<dyn DataLinkReceiver + 'static>, ih: InterfaceRecvModeHandle) -> io::Result<()> {
-    let mut cnt = 0;
+fn packet_recv_loop(mut link: Link, rx: Box<dyn DataLinkReceiver + 'static>, ih: InterfaceRecvModeHandle, dst: MacAddr, replies: Option<Replies>, announcer: discovery::Announcer) -> io::Result<()> {
+    let result = run_streams(&mut link, rx, &ih, dst, replies, announcer);
+    if let Err(e) = result.as_ref() {
+        ih.stop.fail(e);
+    }
+    let mut cm = ih.recv_manager.lock().unwrap();
+    result.and(close_streams(&mut link, &ih, dst, &mut cm))
+}
+
+// receives until the interface stops, or the channel fails
+fn run_streams(link: &mut Link, mut rx: Box<dyn DataLinkReceiver + 'static>, ih: &InterfaceRecvModeHandle, dst: MacAddr, replies: Option<Replies>, mut announcer: discovery::Announcer) -> io::Result<()> {
+    let mut reported = time::Instant::now();
+    let mut serving = false;
     loop {
+        {
+            let mut cmg = ih.recv_manager.lock().unwrap();
+            let cm = &mut *cmg;
+            if ih.stop.raised() {
+                return Ok(());
+            }
+            if cm.serving != serving {
+                serving = cm.serving;
+                announcer.set_capability(discovery::SERVE, serving);
+            }
+            fatal(announce(link, &mut announcer))?;
+            for tri in cm.resets.drain(..) {
+                fatal(send_control(link, dst, tri.src, packet::EftType::Reset, tri.fileid, 0))?;
+            }
+            for (tri, c) in cm.connections.iter_mut() { // unanswered Requests
+                if c.meta.is_some() || c.cnt > 0 || c.reset {
+                    c.request = None;
+                }
+                if let Some((packet_type, path, timer)) = c.request.as_mut() {
+                    if timer.elapsed().as_millis() > general::REQUEST_INTERVAL as u128 {
+                        *timer = time::Instant::now();
+                        if let Err(e) = send_packet(link, dst, tri.src, *packet_type, tri.fileid, 0, path.clone()) {
+                            if is_fatal(&e) {
+                                return Err(e);
+                            }
+                            c.request = None;
+                            c.failed = Some(e);
+                            ih.rcv_cv.notify_all();
+                        }
+                    }
+                }
+            }
+            let mut reclaimed: Vec<Tri> = Vec::new();
+            for (tri, c) in cm.connections.iter_mut() { // silent senders
+                let silence = match c.last_heard {
+                    Some(heard) if !c.reset && !c.unreachable => heard.elapsed().as_millis(),
+                    _ => continue,
+                };
+                if c.closed {
+                    // waiting for a Fin that may never come
+                    if silence > general::PEER_TIMEOUT as u128 {
+                        reclaimed.push(*tri);
+                    }
+                    continue;
+                }
+                if c.is_complete() || silence < general::KEEPALIVE_INTERVAL as u128 {
+                    continue;
+                }
+                if c.keepalive.elapsed().as_millis() > general::KEEPALIVE_INTERVAL as u128 {
+                    if c.keepalives >= general::MAX_KEEPALIVES {
+                        c.unreachable = true;
+                        if let Some(progress) = cm.progress.as_ref() {
+                            c.report(tri, progress);
+                        }
+                        ih.rcv_cv.notify_all();
+                        continue;
+                    }
+                    c.keepalive = time::Instant::now();
+                    c.keepalives += 1;
+                    fatal(send_control(link, dst, tri.src, packet::EftType::Keepalive, tri.fileid, 0))?;
+                }
+            }
+            for tri in reclaimed {
+                cm.connections.remove(&tri);
+                ih.rcv_cv.notify_all();
+            }
+            if let Some(progress) = cm.progress.as_ref().filter(|_| {
+                reported.elapsed().as_millis() >= general::PROGRESS_INTERVAL as u128
+            }) {
+                reported = time::Instant::now();
+                for (tri, c) in cm.connections.iter_mut() { // running transfers
+                    c.report(tri, progress);
+                }
+            }
+            for (tri, c) in cm.connections.iter_mut() { // multicast losses
+                if c.closed || c.is_complete() {
+                    continue;
+                }
+                let missing = match c.multicast.as_ref() {
+                    Some(group) if group.nack_due() => group.missing(&c.flag4buffer),
+                    _ => continue,
+                };
+                let group = c.multicast.as_mut().unwrap();
+                group.backoff(dst);
+                if let Some(missing) = missing {
+                    fatal(send_packet(link, dst, group.group(), packet::EftType::Nack, tri.fileid, 0, missing.to_bytes()))?;
+                }
+            }
+        }
         match rx.next() {
             Ok(frame) => {
                 let frame = EthernetPacket::new(frame).unwrap();
-                if frame.get_ethertype() != EtherType(0xEF7) {
-                    continue;
-                }
+                let packet = if let Some(p) = link.framing.payload(&frame).and_then(|p| packet::EftPacket::from_raw(p).ok()) {
+                    p
+                } else {
+                    continue
+                };
 
-                let packet = if let Ok(p) = packet::EftPacket::from_raw(frame.payload().to_vec()) {
+                if let Some(r) = replies.as_ref() {
+                    if frame.get_source() == dst { // our own frames
+                        continue;
+                    }
+                    // Nacks are heard by the sender and the other members alike
+                    let nack = packet.header.packet_type == packet::EftType::Nack as u8;
+                    if nack || r.claims(&frame, &packet) {
+                        if let Some(p) = authenticate(&r.ih.crypto, &frame, packet.clone()) {
+                            r.tx.send(Message::reply(&frame, p)).ok(); // the send loop stops with us
+                        }
+                        if !nack {
+                            continue;
+                        }
+                    }
+                }
+                let packet = if let Some(p) = authenticate(&link.crypto, &frame, packet) {
                     p
                 } else {
                     continue
                 };
 
+                if packet.header.packet_type == packet::EftType::Solicit as u8 {
+                    announcer.solicit();
+                    continue;
+                }
+
                 let mut cmg = ih.recv_manager.lock().unwrap();
                 let cm = &mut *cmg;
                 let t = Tri {
@@ -498,86 +2229,486 @@ fn packet_recv_loop(mut tx: Box<dyn DataLinkSender + 'static>, mut rx: Box<dyn D
                     fileid: packet.header.id,
                 };
 
+                if packet.header.packet_type == packet::EftType::Probe as u8 {
+                    // echoes carry no padding, so receivers never answer each other
+                    if t.src != dst && !packet.payload.is_empty() {
+                        fatal(send_control(link, dst, t.src, packet::EftType::Probe, t.fileid, packet.header.offset))?;
+                    }
+                    continue;
+                }
+
+                if packet.header.packet_type == packet::EftType::Nack as u8 { // another member's Nack
+                    if t.src == dst {
+                        continue;
+                    }
+                    for (tri, c) in cm.connections.iter_mut() {
+                        if tri.dst != t.dst || tri.fileid != t.fileid {
+                            continue;
+                        }
+                        if let Some(group) = c.multicast.as_mut() {
+                            group.on_overheard(dst, &c.flag4buffer, &packet.payload);
+                        }
+                    }
+                    continue;
+                }
+
+                if packet.header.packet_type == packet::EftType::Request as u8
+                    || packet.header.packet_type == packet::EftType::List as u8
+                    || packet.header.packet_type == packet::EftType::Stat as u8 {
+                    if cm.serving {
+                        if let Ok(path) = String::from_utf8(packet.payload) {
+                            cm.queue_request(t.src, t.fileid, packet.header.packet_type, path);
+                            ih.rcv_cv.notify_all();
+                        }
+                    }
+                    continue;
+                }
+
+                if packet.header.packet_type == packet::EftType::Fin as u8 {
+                    match cm.connections.entry(t) {
+                        Entry::Occupied(mut s) => {
+                            if !s.get().is_complete() {
+                                let mut c = s.remove();
+                                if let Some(progress) = cm.progress.as_ref() {
+                                    c.reset = true;
+                                    c.report(&t, progress);
+                                }
+                                fatal(send_control(link, dst, t.src, packet::EftType::Reset, t.fileid, 0))?;
+                                ih.rcv_cv.notify_all();
+                                continue;
+                            }
+                            if s.get().closed {
+                                s.remove();
+                                ih.rcv_cv.notify_all();
+                            } else {
+                                s.get_mut().fin = true;
+                            }
+                        },
+                        // already released: the sender gives up after MAX_FIN_RETRIES
+                        Entry::Vacant(_) => continue,
+                    }
+                    fatal(send_control(link, dst, t.src, packet::EftType::Fin, t.fileid, packet.header.offset))?;
+                    continue;
+                }
+
                 match cm.connections.entry(t) {
                     Entry::Occupied(mut s) => {
                         if packet.header.packet_type == packet::EftType::Ack as u8 {
                             continue;
                         }
-                        if let Ok(b) = s.get_mut().on_packet(packet.header.offset, packet.header.packet_type, &packet.payload) {
-                            send_ack(&mut tx, t.dst, t.src, packet.header.id, packet.header.offset);
+                        let c = s.get_mut();
+                        c.last_heard = Some(time::Instant::now());
+                        c.keepalives = 0;
+                        if packet.header.packet_type == packet::EftType::Keepalive as u8 {
+                            continue;
+                        }
+                        if packet.header.packet_type == packet::EftType::Reset as u8 {
+                            if s.get().closed {
+                                s.remove();
+                            } else {
+                                s.get_mut().reset = true;
+                                if let Some(progress) = cm.progress.as_ref() {
+                                    s.get_mut().report(&t, progress);
+                                }
+                            }
+                            ih.rcv_cv.notify_all();
+                            continue;
+                        }
+                        if packet.header.packet_type == packet::EftType::Meta as u8 {
+                            let c = s.get_mut();
+                            let b = if let Ok(b) = c.on_meta(&packet.payload) {
+                                b
+                            } else {
+                                continue
+                            };
+                            if c.multicast.is_none() {
+                                fatal(send_control(link, dst, t.src, packet::EftType::Meta, packet.header.id, 0))?;
+                            } else if b {
+                                fatal(send_packet(link, dst, t.src, packet::EftType::Sack, packet.header.id, c.cnt as u16, c.flag4buffer.to_bytes()))?;
+                            }
                             if b {
-                                cnt += 1;
-                                eprint!("file received: {}\r", cnt);
+                                if let Some(progress) = cm.progress.as_ref() {
+                                    c.report(&t, progress);
+                                }
                                 ih.rcv_cv.notify_all() // ファイル受信完了
                             }
+                            continue;
+                        }
+                        if packet.header.packet_type != packet::EftType::Data as u8
+                            && packet.header.packet_type != packet::EftType::DataEnd as u8
+                            && packet.header.packet_type != packet::EftType::Parity as u8 {
+                            continue;
+                        }
+                        let c = s.get_mut();
+                        if c.unconfirmed { // until the Meta, what we hold may be of another file
+                            continue;
+                        }
+                        let (sack, b) = if packet.header.packet_type == packet::EftType::Parity as u8 {
+                            match c.on_parity(&packet.payload) {
+                                Ok((recovered, b)) if recovered > 0 => (true, b),
+                                _ => continue,
+                            }
+                        } else {
+                            // a duplicate means our ack was lost or the sender restarted: tell it everything we hold
+                            let sack = c.resumed || c.flag4buffer.isset(packet.header.offset as usize).unwrap_or(false);
+                            let b = if let Ok(b) = c.on_packet(packet.header.offset, packet.header.packet_type, &packet.payload) {
+                                b
+                            } else {
+                                continue
+                            };
+                            match c.fec.block_of(packet.header.offset) {
+                                Some(block) => match c.repair(block) {
+                                    Ok((recovered, repaired)) => (sack || recovered > 0, b || repaired),
+                                    Err(_) => (sack, b),
+                                },
+                                None => (sack, b),
+                            }
+                        };
+                        if let Some(group) = c.multicast.as_mut() {
+                            group.on_data(packet.header.offset);
+                            // members only report completion, in reply to the DataEnd probe as well
+                            if c.is_complete() && (b || packet.header.packet_type == packet::EftType::DataEnd as u8) {
+                                fatal(send_packet(link, dst, t.src, packet::EftType::Sack, packet.header.id, c.cnt as u16, c.flag4buffer.to_bytes()))?;
+                            }
+                        } else if sack {
+                            c.resumed = false;
+                            fatal(send_packet(link, dst, t.src, packet::EftType::Sack, packet.header.id, c.cnt as u16, c.flag4buffer.to_bytes()))?;
+                        } else {
+                            fatal(send_control(link, dst, t.src, packet::EftType::Ack, packet.header.id, packet.header.offset))?;
+                        }
+                        if b {
+                            if let Some(progress) = cm.progress.as_ref() {
+                                c.report(&t, progress);
+                            }
+                            ih.rcv_cv.notify_all() // ファイル受信完了
                         }
                     },
                     _ => continue,
                 }
             },
+            Err(e) if is_fatal(&e) => return Err(e),
             Err(_) => continue,
         }
     }
 }
 
+// ends every stream once the interface stops: files already read are confirmed
+// with Fin, senders of the others get Reset. Blocked reads fail
+fn close_streams(link: &mut Link, ih: &InterfaceRecvModeHandle, dst: MacAddr, cm: &mut RecvConnectionManager) -> io::Result<()> {
+    let mut result = Ok(());
+    for (tri, mut c) in cm.connections.drain() {
+        if c.last_heard.is_none() || c.reset { // no sender to tell
+            continue;
+        }
+        if c.closed {
+            result = result.and(send_control(link, dst, tri.src, packet::EftType::Fin, tri.fileid, 0));
+            continue;
+        }
+        result = result.and(send_control(link, dst, tri.src, packet::EftType::Reset, tri.fileid, 0));
+        if let Some(progress) = cm.progress.as_ref() {
+            c.reset = true;
+            c.report(&tri, progress);
+        }
+    }
+    for tri in cm.resets.drain(..) {
+        result = result.and(send_control(link, dst, tri.src, packet::EftType::Reset, tri.fileid, 0));
+    }
+    result = result.and(link.flush());
+    ih.stopped.store(true, Ordering::Relaxed);
+    ih.rcv_cv.notify_all();
+    result
+}
+
 struct RecvConnection {
     buffer: Vec<Vec<u8>>,
     flag4buffer: utils::Flags,
     cnt: usize,
+    closed: bool, // data was handed to the application
+    fin: bool,
+    reset: bool,
+    resumed: bool,
+    unconfirmed: bool, // resumed, and the sender's Meta has yet to show it is the same file
+    sidecar: Option<resume::Sidecar>,
+    destination: Option<String>,
+    fec: fec::Decoder,
+    multicast: Option<multicast::RecvGroup>,
+    meta: Option<meta::Metadata>,
+    request: Option<(packet::EftType, Vec<u8>, time::Instant)>, // Request, List or Stat until the server answers
+    last_heard: Option<time::Instant>, // None until the sender shows up
+    keepalive: time::Instant,
+    keepalives: usize, // unanswered
+    unreachable: bool,
+    failed: Option<io::Error>, // the Request could not be sent
+    tracker: progress::Tracker,
+    generation: u64, // see RecvConnectionManager::open
 }
 
 impl RecvConnection {
+    fn new() -> Self {
+        RecvConnection {
+            buffer: vec![Default::default(); general::MAX_OFFSET_LENGTH],
+            flag4buffer: utils::Flags::new(),
+            cnt: 0,
+            closed: false,
+            fin: false,
+            reset: false,
+            resumed: false,
+            unconfirmed: false,
+            sidecar: None,
+            destination: None,
+            fec: Default::default(),
+            multicast: None,
+            meta: None,
+            request: None,
+            last_heard: None,
+            keepalive: time::Instant::now(),
+            keepalives: 0,
+            unreachable: false,
+            failed: None,
+            tracker: progress::Tracker::new(),
+            generation: 0,
+        }
+    }
+
+    fn on_meta(&mut self, payload: &[u8]) -> io::Result<bool> {
+        let metadata = meta::Metadata::from_raw(payload)?;
+        if let Some(sidecar) = self.sidecar.as_mut() {
+            if !sidecar.confirm(resume::identity(&metadata))? { // another file was sent on this fileid
+                self.discard();
+            }
+            self.unconfirmed = false;
+        }
+        if self.meta.is_some() {
+            return Ok(false);
+        }
+        if let Some(sidecar) = self.sidecar.as_mut() {
+            sidecar.append(0, packet::EftType::Meta as u8, payload)?;
+        }
+        self.meta = Some(metadata);
+        Ok(self.is_complete())
+    }
+
+    // forgets what was resumed of another file
+    fn discard(&mut self) {
+        self.buffer = vec![Default::default(); general::MAX_OFFSET_LENGTH];
+        self.flag4buffer = utils::Flags::new();
+        self.cnt = 0;
+        self.fec = Default::default();
+        self.meta = None;
+        self.resumed = false;
+        self.tracker = progress::Tracker::new();
+    }
+
+    // returns the number of recovered fragments and whether the file is complete
+    fn on_parity(&mut self, payload: &[u8]) -> io::Result<(usize, bool)> {
+        if self.closed {
+            return Ok((0, false));
+        }
+        let block = self.fec.on_parity(payload)?;
+        self.repair(block)
+    }
+
+    fn repair(&mut self, block: u16) -> io::Result<(usize, bool)> {
+        if self.closed {
+            return Ok((0, false));
+        }
+        let recovered = self.fec.recover(block, &self.buffer, &self.flag4buffer)?;
+        let mut complete = false;
+        for (offset, last, data) in recovered.iter() {
+            let packet_type = if *last {
+                packet::EftType::DataEnd
+            } else {
+                packet::EftType::Data
+            };
+            complete |= self.on_packet(*offset, packet_type as u8, data)?;
+        }
+        Ok((recovered.len(), complete))
+    }
+
     fn on_packet<'a>(&mut self, offset: u16, packet_type: u8, data: &'a [u8]) -> io::Result<bool> {
         if self.flag4buffer.isset(offset as usize)? {
             return Ok(false);
         }
+        if let Some(sidecar) = self.sidecar.as_mut() { // persist before the fragment is acknowledged
+            sidecar.append(offset, packet_type, data)?;
+        }
         self.flag4buffer.set(offset as usize)?;
         self.buffer[offset as usize] = data.to_vec();
 
         self.cnt += 1;
+        self.tracker.add(data.len());
 
         if packet_type == packet::EftType::DataEnd as u8 {
             self.flag4buffer.set_length(offset as usize + 1)?;
         }
 
-        if let Ok(l) = self.flag4buffer.get_length() {
-            if self.cnt == l {
-                return Ok(true);
-            }
+        Ok(self.is_complete())
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.flag4buffer.get_length() {
+            Ok(l) => l == self.cnt && self.meta.is_some(),
+            Err(_) => false,
         }
+    }
 
-        Ok(false)
+    // a Running report when one is due, the final one once the transfer ended
+    fn report(&mut self, tri: &Tri, progress: &mpsc::Sender<Progress>) {
+        if self.meta.is_none() && self.cnt == 0 && !self.reset { // nothing arrived yet
+            return;
+        }
+        let status = if self.reset || self.unreachable || self.failed.is_some() {
+            Status::Failed
+        } else if self.is_complete() {
+            Status::Done
+        } else {
+            Status::Running
+        };
+        let due = match status {
+            Status::Running => self.tracker.due(),
+            _ => self.tracker.end(),
+        };
+        if !due {
+            return;
+        }
+        let (name, total_bytes) = match self.meta.as_ref() {
+            Some(m) if m.compression.is_none() => (&*m.path, Some(m.size)),
+            Some(m) => (&*m.path, None),
+            None => ("", None),
+        };
+        progress.send(self.tracker.progress(tri.fileid, tri.src, name, self.flag4buffer.get_length().ok(), total_bytes, status)).ok();
     }
 }
 
 pub struct RecvStream {
     tri: Tri,
+    generation: u64,
     ih: InterfaceRecvModeHandle,
+    metadata: Option<meta::Metadata>,
 }
 
 impl RecvStream {
     pub fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut cm = self.ih.recv_manager.lock().unwrap();
         loop {
-            let mut cm = self.ih.recv_manager.lock().unwrap();
-            cm = self.ih.rcv_cv.wait(cm).unwrap();
-            let c = cm.connections.get_mut(&self.tri).ok_or_else(|| {
+            if self.ih.stopped.load(Ordering::Relaxed) {
+                return Err(self.ih.stop.reason());
+            }
+            if cm.cancelled.contains(&self.generation) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection aborted"));
+            }
+            let c = cm.stream(&self.tri, self.generation).ok_or_else(|| {
                 io::Error::new(io::ErrorKind::Other, "stream was terminated unexpectedly")
             })?;
-            if let Ok(l) = c.flag4buffer.get_length() {
-                if l != c.cnt {
-                    continue;
-                }
-            } else {
+            if c.reset {
+                cm.connections.remove(&self.tri);
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer"));
+            }
+            if c.unreachable { // a resume sidecar stays behind for the next attempt
+                cm.connections.remove(&self.tri);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "peer unreachable"));
+            }
+            if let Some(e) = c.failed.take() {
+                cm.connections.remove(&self.tri);
+                return Err(e);
+            }
+            if c.closed {
+                return Err(io::Error::new(io::ErrorKind::Other, "stream was already read"));
+            }
+            if !c.is_complete() {
+                cm = self.ih.rcv_cv.wait(cm).unwrap();
                 continue;
             }
-            let raw_file: Vec<u8> = c.buffer[0..c.cnt].iter().fold(Vec::new(),
+            let mut raw_file: Vec<u8> = c.buffer[0..c.cnt].iter().fold(Vec::new(),
                 |mut acc, f| {
                     acc.extend_from_slice(f);
                     acc
                 }
             );
-            // cm.connections.remove(&self.tri);
+            if let Some(metadata) = c.meta.as_ref() {
+                if let Some(compression) = metadata.compression {
+                    raw_file = compression.decompress(&raw_file, metadata.size)?;
+                }
+                if raw_file.len() as u64 != metadata.size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "size mismatch"));
+                }
+                if metadata.digest.map_or(false, |digest| Sha256::digest(&raw_file)[..] != digest[..]) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "digest mismatch"));
+                }
+            }
+            if let Some(filepath) = c.destination.as_ref() {
+                File::create(filepath)?.write_all(&raw_file)?;
+                if let Some(sidecar) = c.sidecar.take() {
+                    sidecar.remove()?;
+                }
+            }
+            self.metadata = c.meta.clone();
+            // keep the flags until the sender's Fin so that retransmissions are still acknowledged
+            if c.fin {
+                cm.connections.remove(&self.tri);
+            } else {
+                c.closed = true;
+                c.buffer = Vec::new();
+            }
             return Ok(raw_file);
         }
     }
-}
\ No newline at end of file
+
+    // available once the sender's Meta packet has arrived
+    #[allow(dead_code)]
+    pub fn metadata(&self) -> Option<meta::Metadata> {
+        if self.metadata.is_some() {
+            return self.metadata.clone();
+        }
+        let mut cm = self.ih.recv_manager.lock().unwrap();
+        cm.stream(&self.tri, self.generation).and_then(|c| c.meta.clone())
+    }
+
+    // reads the file and writes it under `dir` with the sender's name and attributes
+    pub fn save(&mut self, dir: &str) -> io::Result<PathBuf> {
+        let data = self.read()?;
+        let metadata = self.metadata.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "metadata was not received")
+        })?;
+        meta::write_file(Path::new(dir), metadata, &data)
+    }
+
+    #[allow(dead_code)]
+    pub fn abort(&mut self) -> io::Result<()> {
+        let mut cm = self.ih.recv_manager.lock().unwrap();
+        if cm.stream(&self.tri, self.generation).is_none() {
+            return Err(io::Error::new(io::ErrorKind::Other, "stream was already closed"));
+        }
+        let c = cm.connections.remove(&self.tri).unwrap();
+        cm.resets.push(self.tri);
+        if let Some(sidecar) = c.sidecar {
+            sidecar.remove()?;
+        }
+        Ok(())
+    }
+
+    // aborts the stream from another thread, waking a blocked read
+    #[allow(dead_code)]
+    pub fn cancel_token(&self) -> CancelToken {
+        CancelToken { target: Cancel::Recv(self.ih.clone(), self.tri, self.generation) }
+    }
+}
+
+impl Drop for RecvStream {
+    fn drop(&mut self) {
+        let mut cm = self.ih.recv_manager.lock().unwrap();
+        if cm.cancelled.remove(&self.generation) {
+            return;
+        }
+        let (unread, reset) = match cm.stream(&self.tri, self.generation) {
+            Some(c) => (!c.closed, c.reset),
+            None => (false, false),
+        };
+        if unread {
+            cm.connections.remove(&self.tri);
+            if !reset {
+                cm.resets.push(self.tri);
+            }
+        }
+    }
+}
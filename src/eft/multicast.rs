@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    io,
+    time,
+};
+
+use pnet::util::MacAddr;
+
+use crate::general;
+use crate::utils;
+
+// One-to-many transfers. The sender multicasts every fragment once to the group
+// address and never expects per-fragment acks. Receivers multicast a Nack (bitmap
+// of missing offsets) to the group after a randomized delay; a receiver that
+// overhears a Nack covering everything it misses suppresses its own. The sender
+// aggregates Nacks for a short window and retransmits their union once. A member
+// reports completion with a unicast Sack, and the sender periodically re-sends
+// the DataEnd fragment as a probe until every member has done so or reset.
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Member {
+    Pending,
+    Complete,
+    Reset,
+}
+
+pub struct SendGroup {
+    members: HashMap<MacAddr, Member>,
+    sent: utils::Flags,
+    nacked: utils::Flags,
+    nacked_since: Option<time::Instant>,
+    probe: time::Instant,
+}
+
+impl SendGroup {
+    pub fn new(members: &[MacAddr]) -> Self {
+        Self {
+            members: members.iter().map(|m| (*m, Member::Pending)).collect(),
+            sent: utils::Flags::new(),
+            nacked: utils::Flags::new(),
+            nacked_since: None,
+            probe: time::Instant::now(),
+        }
+    }
+
+    pub fn contains(&self, member: MacAddr) -> bool {
+        self.members.contains_key(&member)
+    }
+
+    pub fn on_nack(&mut self, member: MacAddr, bitmap: &[u8]) -> io::Result<()> {
+        if !self.contains(member) {
+            return Ok(());
+        }
+        let missing = utils::Flags::from_bytes(bitmap)?;
+        for offset in 0..general::MAX_OFFSET_LENGTH {
+            if missing.isset(offset)? {
+                self.nacked.set(offset)?;
+            }
+        }
+        if self.nacked_since.is_none() {
+            self.nacked_since = Some(time::Instant::now());
+        }
+        Ok(())
+    }
+
+    // returns true once no member is left that neither holds the whole file nor reset
+    pub fn on_complete(&mut self, member: MacAddr) -> bool {
+        self.leave(member, Member::Complete)
+    }
+
+    // as on_complete, for a member that gave up on the file
+    pub fn on_reset(&mut self, member: MacAddr) -> bool {
+        self.leave(member, Member::Reset)
+    }
+
+    fn leave(&mut self, member: MacAddr, state: Member) -> bool {
+        if let Some(m) = self.members.get_mut(&member) {
+            if *m == Member::Pending {
+                *m = state;
+            }
+        }
+        self.members.values().all(|m| *m != Member::Pending)
+    }
+
+    // fails unless every member holds the whole file
+    pub fn outcome(&self) -> io::Result<()> {
+        let incomplete = self.members.values().filter(|m| **m != Member::Complete).count();
+        if incomplete > 0 {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                format!("{} of {} members did not receive the file", incomplete, self.members.len())));
+        }
+        Ok(())
+    }
+
+    // offsets that are due: the first pass, the aggregated Nacks and the probe
+    pub fn due(&mut self, fragments: usize) -> Vec<u16> {
+        let mut due: Vec<u16> = Vec::new();
+        for offset in 0..fragments {
+            if !self.sent.isset(offset).unwrap_or(true) {
+                self.sent.set(offset).ok();
+                due.push(offset as u16);
+            }
+        }
+        if !due.is_empty() {
+            self.probe = time::Instant::now();
+            return due;
+        }
+
+        if let Some(since) = self.nacked_since {
+            if since.elapsed().as_millis() > general::NACK_AGGREGATION_WINDOW as u128 {
+                for offset in 0..fragments {
+                    if self.nacked.isset(offset).unwrap_or(false) {
+                        due.push(offset as u16);
+                    }
+                }
+                self.nacked = utils::Flags::new();
+                self.nacked_since = None;
+                self.probe = time::Instant::now();
+            }
+        }
+
+        if due.is_empty() && fragments > 0 && self.probe.elapsed().as_millis() > general::MULTICAST_PROBE_INTERVAL as u128 {
+            self.probe = time::Instant::now();
+            due.push(fragments as u16 - 1);
+        }
+        due
+    }
+}
+
+pub struct RecvGroup {
+    group: MacAddr,
+    nack_deadline: time::Instant,
+    highest: Option<u16>,
+}
+
+impl RecvGroup {
+    pub fn new(group: MacAddr, member: MacAddr) -> Self {
+        let mut g = Self {
+            group: group,
+            nack_deadline: time::Instant::now(),
+            highest: None,
+        };
+        g.backoff(member);
+        g
+    }
+
+    pub fn group(&self) -> MacAddr {
+        self.group
+    }
+
+    pub fn on_data(&mut self, offset: u16) {
+        self.highest = Some(self.highest.map_or(offset, |h| h.max(offset)));
+    }
+
+    // offsets we know to be missing: gaps below the highest offset seen, or below the length once known
+    pub fn missing(&self, held: &utils::Flags) -> Option<utils::Flags> {
+        let upper = match held.get_length() {
+            Ok(length) => length,
+            Err(_) => self.highest? as usize,
+        };
+        let mut missing = utils::Flags::new();
+        let mut any = false;
+        for offset in 0..upper {
+            if !held.isset(offset).ok()? {
+                missing.set(offset).ok()?;
+                any = true;
+            }
+        }
+        if any {
+            Some(missing)
+        } else {
+            None
+        }
+    }
+
+    // another member already asked for everything we miss
+    pub fn on_overheard(&mut self, member: MacAddr, held: &utils::Flags, bitmap: &[u8]) {
+        let overheard = if let Ok(f) = utils::Flags::from_bytes(bitmap) {
+            f
+        } else {
+            return
+        };
+        if let Some(missing) = self.missing(held) {
+            let covered = (0..general::MAX_OFFSET_LENGTH).all(|offset| {
+                !missing.isset(offset).unwrap_or(false) || overheard.isset(offset).unwrap_or(false)
+            });
+            if covered {
+                self.backoff(member);
+            }
+        }
+    }
+
+    pub fn nack_due(&self) -> bool {
+        time::Instant::now() >= self.nack_deadline
+    }
+
+    // randomized so that members do not Nack in lockstep
+    pub fn backoff(&mut self, member: MacAddr) {
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let jitter = (nanos ^ ((member.5 as u32) << 8) ^ member.4 as u32) as u64 % general::NACK_BACKOFF;
+        self.nack_deadline = time::Instant::now() + time::Duration::from_millis(general::NACK_INTERVAL + jitter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: MacAddr = MacAddr(2, 0, 0, 0, 0, 1);
+    const B: MacAddr = MacAddr(2, 0, 0, 0, 0, 2);
+
+    #[test]
+    fn members_complete() {
+        let mut group = SendGroup::new(&[A, B]);
+        assert!(!group.on_complete(A));
+        assert!(group.outcome().is_err());
+        assert!(group.on_complete(B));
+        assert!(group.outcome().is_ok());
+    }
+
+    #[test]
+    fn members_reset() {
+        let mut group = SendGroup::new(&[A, B]);
+        assert!(!group.on_reset(A));
+        assert!(group.on_reset(B));
+        assert_eq!(group.outcome().unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+
+        // a member keeps the file it reported
+        let mut group = SendGroup::new(&[A, B]);
+        group.on_complete(A);
+        group.on_reset(A);
+        assert!(group.on_reset(B));
+        assert!(group.outcome().unwrap_err().to_string().starts_with("1 of 2"));
+    }
+
+    #[test]
+    fn malformed_nack() {
+        let mut group = SendGroup::new(&[A]);
+        assert!(group.on_nack(A, &[0; 3]).is_err());
+        assert!(group.nacked_since.is_none());
+    }
+}
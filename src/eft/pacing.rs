@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    io,
+    time,
+};
+
+use pnet::util::MacAddr;
+
+// Token buckets capping the rate at which fragments leave the interface, for the
+// interface as a whole and per destination. A bucket holds up to `burst` bytes
+// and refills at `rate` bytes per second; a fragment may go once the bucket is
+// not empty and may drive it into debt, so frames are paced by how fast tokens
+// accrue rather than sent in bursts. Control packets are not limited.
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RateLimit {
+    pub rate: u64, // bytes per second
+    pub burst: usize, // bytes
+}
+
+impl RateLimit {
+    #[allow(dead_code)]
+    pub fn new(rate: u64, burst: usize) -> io::Result<Self> {
+        if rate == 0 || burst == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid rate limit"));
+        }
+        Ok(Self {
+            rate: rate,
+            burst: burst,
+        })
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: time::Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit: limit,
+            tokens: limit.burst as f64,
+            last: time::Instant::now(),
+        }
+    }
+
+    fn set(&mut self, limit: RateLimit) {
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+    }
+
+    fn ready(&mut self, now: time::Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.last = now;
+        self.tokens > 0.0
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    // until the bucket holds a token again, as of the last call to ready
+    fn wait(&self) -> time::Duration {
+        if self.tokens > 0.0 {
+            return time::Duration::from_secs(0);
+        }
+        time::Duration::from_secs_f64((1.0 - self.tokens) / self.limit.rate as f64)
+    }
+}
+
+#[derive(Default)]
+pub struct Limits {
+    interface: Option<TokenBucket>,
+    peers: HashMap<MacAddr, TokenBucket>,
+}
+
+impl Limits {
+    pub fn set_interface(&mut self, limit: Option<RateLimit>) {
+        self.interface = match (self.interface.take(), limit) {
+            (Some(mut bucket), Some(limit)) => {
+                bucket.set(limit);
+                Some(bucket)
+            },
+            (None, Some(limit)) => Some(TokenBucket::new(limit)),
+            (_, None) => None,
+        };
+    }
+
+    pub fn set_peer(&mut self, dst: MacAddr, limit: Option<RateLimit>) {
+        match limit {
+            Some(limit) => {
+                self.peers.entry(dst)
+                    .and_modify(|bucket| bucket.set(limit))
+                    .or_insert_with(|| TokenBucket::new(limit));
+            },
+            None => {
+                self.peers.remove(&dst);
+            },
+        }
+    }
+
+    // charges `bytes` to `dst` and the interface if both have tokens left
+    pub fn admit(&mut self, dst: MacAddr, bytes: usize) -> bool {
+        let now = time::Instant::now();
+        if !self.interface.as_mut().map_or(true, |bucket| bucket.ready(now)) {
+            return false;
+        }
+        if !self.peers.get_mut(&dst).map_or(true, |bucket| bucket.ready(now)) {
+            return false;
+        }
+        if let Some(bucket) = self.interface.as_mut() {
+            bucket.take(bytes);
+        }
+        if let Some(bucket) = self.peers.get_mut(&dst) {
+            bucket.take(bytes);
+        }
+        true
+    }
+
+    // how long until `dst` may be admitted again
+    pub fn wait(&self, dst: MacAddr) -> time::Duration {
+        let interface = self.interface.as_ref().map_or(time::Duration::from_secs(0), |bucket| bucket.wait());
+        let peer = self.peers.get(&dst).map_or(time::Duration::from_secs(0), |bucket| bucket.wait());
+        interface.max(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_for_tokens() {
+        let dst = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let mut limits = Limits::default();
+        limits.set_interface(Some(RateLimit::new(1000, 100).unwrap()));
+        assert!(limits.admit(dst, 600));
+        assert!(!limits.admit(dst, 600));
+        let wait = limits.wait(dst);
+        assert!(wait > time::Duration::from_millis(490) && wait <= time::Duration::from_millis(501), "{:?}", wait);
+
+        limits.set_peer(dst, Some(RateLimit::new(100, 100).unwrap()));
+        assert!(limits.wait(dst) >= time::Duration::from_millis(490));
+        assert_eq!(limits.wait(MacAddr::new(2, 0, 0, 0, 0, 2)), limits.wait(dst));
+        limits.set_interface(None);
+        assert_eq!(limits.wait(dst), time::Duration::from_secs(0));
+    }
+}
@@ -4,7 +4,6 @@ use std::{
 };
 
 use crate::general;
-use crate::utils;
 
 // 0                   1                   2                   3   
 // 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 
@@ -15,11 +14,48 @@ use crate::utils;
 // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 //
 //                Example Ethernet File Transfer Header
+//
+// Close sequence:
+//   sender                            receiver
+//     | ---- Meta ----------------------> |
+//     | <--- Meta ----------------------- |
+//     | ---- Data / DataEnd ------------> |
+//     | <--- Ack (every offset) --------- |
+//     | ---- Fin (retransmitted) -------> |  all offsets received
+//     | <--- Fin ------------------------ |  state released on both sides
+//
+// Either side may send Reset at any time to abort the transfer.
+//
+// Pull mode, the server replies with a sender's Meta or with Reset:
+//   client                            server
+//     | ---- Request (retransmitted) --> |  until the Meta arrives
+//     | <--- Meta ---------------------- |  then as above with the roles swapped
+//
+// List and Stat work the same, their answer is the transferred file, see remote.rs.
+//
+// A receiver that hears nothing for KEEPALIVE_INTERVAL sends Keepalive, which the
+// sender echoes. Unanswered, either side fails the transfer as peer unreachable.
+//
+// With a pre-shared key the payload of every packet is encrypted, see crypto.rs.
 
+#[derive(Copy, Clone)]
 pub enum EftType {
     Data = 0,
     DataEnd = 1,
     Ack = 2,
+    Fin = 3,
+    Reset = 4,
+    Sack = 5, // payload: bitmap of every offset the receiver holds
+    Parity = 6, // payload: see fec.rs
+    Nack = 7, // payload: bitmap of missing offsets, multicast only
+    Meta = 8, // payload: see meta.rs, echoed without payload as its ack
+    Probe = 9, // offset: probed packet length, payload: padding, echoed without payload
+    Request = 10, // payload: path under the server's root
+    List = 11, // payload: directory under the server's root, empty for the root itself
+    Stat = 12, // payload: path under the server's root, empty for the root itself
+    Announce = 13, // payload: see discovery.rs, broadcast
+    Solicit = 14, // broadcast, answered with Announce
+    Keepalive = 15, // offset: 0 from a silent receiver, 1 for the sender's echo
 }
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -61,22 +97,18 @@ pub struct EftPacket {
 }
 
 impl EftPacket {
-    pub fn from_raw(mut raw_packet: Vec<u8>) -> io::Result<Self> {
-        let header: EftPacketHeader = EftPacketHeader::from_raw(&raw_packet)?;
+    // copies only the payload out of `raw_packet`, which may live in a receive ring
+    pub fn from_raw(raw_packet: &[u8]) -> io::Result<Self> {
+        let header: EftPacketHeader = EftPacketHeader::from_raw(raw_packet)?;
 
-        if header.packet_type == EftType::Ack as u8 {
-            raw_packet.resize(8, 0);
-        } else {
-            utils::rstrip_null(&mut raw_packet);
-        }
-        
-        if raw_packet.len() != header.total_length as usize {
+        // frames shorter than the ethernet minimum arrive zero padded
+        if raw_packet.len() < header.total_length as usize || (header.total_length as usize) < general::EFT_HEADER_LENGTH {
             return Err(io::Error::new(io::ErrorKind::Other, "length error"));
         }
 
         Ok(Self {
             header: header,
-            payload: raw_packet[general::EFT_HEADER_LENGTH..].to_vec(),
+            payload: raw_packet[general::EFT_HEADER_LENGTH..header.total_length as usize].to_vec(),
         })
     }
 
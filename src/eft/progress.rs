@@ -0,0 +1,116 @@
+use std::{
+    mem,
+    time,
+};
+
+use pnet::util::MacAddr;
+
+use crate::general;
+
+// Snapshots of a transfer, sent to the channel given to set_progress of either mode
+// at most every PROGRESS_INTERVAL while it runs, and once when it ends. Senders count
+// acknowledged fragments, receivers the ones they hold; bytes are as transferred,
+// that is after compression.
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Running,
+    Done,
+    Failed, // the reason is reported by SendHandle::wait or RecvStream::read
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub fileid: u16,
+    pub peer: MacAddr,
+    pub name: String, // empty until the receiver has the Meta packet
+    pub fragments: usize,
+    pub total_fragments: Option<usize>, // receivers learn it from DataEnd
+    pub bytes: u64,
+    pub total_bytes: Option<u64>, // unknown to receivers of compressed files
+    pub elapsed: time::Duration,
+    pub status: Status,
+}
+
+impl Progress {
+    // bytes per second
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.elapsed.as_secs_f64();
+        if elapsed > 0.0 {
+            self.bytes as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    pub fn eta(&self) -> Option<time::Duration> {
+        let remaining = self.total_bytes?.saturating_sub(self.bytes);
+        let rate = self.rate();
+        if rate > 0.0 {
+            Some(time::Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            None
+        }
+    }
+}
+
+// counts for one connection and decides when it reports
+pub struct Tracker {
+    started: time::Instant,
+    reported: Option<time::Instant>,
+    changed: bool,
+    ended: bool,
+    fragments: usize,
+    bytes: u64,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self {
+            started: time::Instant::now(),
+            reported: None,
+            changed: true,
+            ended: false,
+            fragments: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.fragments += 1;
+        self.bytes += bytes as u64;
+        self.changed = true;
+    }
+
+    // whether a Running report is due
+    pub fn due(&mut self) -> bool {
+        let interval = self.reported.map_or(true, |reported| {
+            reported.elapsed().as_millis() >= general::PROGRESS_INTERVAL as u128
+        });
+        if !self.changed || !interval || self.ended {
+            return false;
+        }
+        self.reported = Some(time::Instant::now());
+        self.changed = false;
+        true
+    }
+
+    // whether the final report is still to be sent
+    pub fn end(&mut self) -> bool {
+        !mem::replace(&mut self.ended, true)
+    }
+
+    pub fn progress(&self, fileid: u16, peer: MacAddr, name: &str, total_fragments: Option<usize>, total_bytes: Option<u64>, status: Status) -> Progress {
+        Progress {
+            fileid: fileid,
+            peer: peer,
+            name: name.to_string(),
+            fragments: self.fragments,
+            total_fragments: total_fragments,
+            bytes: self.bytes,
+            total_bytes: total_bytes,
+            elapsed: self.started.elapsed(),
+            status: status,
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use sha2::{
+    Digest, Sha256,
+};
+
+use crate::utils;
+use super::tree;
+
+// Answers to List and Stat, sent back as an ordinary file (all integers are big endian):
+//
+//   stat: kind (1) | size (8) | mode (4) | mtime secs (8) | has digest (1) | [sha-256 (32)]
+//         name length (2) | name
+//   listing: entry count (4) | stats ...
+//
+// The mode holds the permission bits only, as in Meta.
+//
+// Only Stat hashes the contents, listings leave the digest out.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: tree::EntryKind,
+    pub name: String,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: i64,
+    pub digest: Option<[u8; 32]>, // regular files only
+}
+
+impl Stat {
+    // follows symlinks, as the file would be sent
+    pub fn from_path(path: &Path, name: &str) -> io::Result<Self> {
+        let m = fs::metadata(path)?;
+        let digest = if m.is_file() {
+            let mut digest = [0; 32];
+            digest.copy_from_slice(&Sha256::digest(&fs::read(path)?));
+            Some(digest)
+        } else {
+            None
+        };
+        Ok(Self {
+            kind: if m.is_dir() { tree::EntryKind::Dir } else { tree::EntryKind::File },
+            name: name.to_string(),
+            size: m.len(),
+            mode: m.mode() & 0o7777, // the kind travels on its own
+            mtime: m.mtime(),
+            digest: digest,
+        })
+    }
+
+    pub fn from_raw(raw: &[u8]) -> io::Result<Self> {
+        Self::parse(&mut utils::Reader::new(raw, "stat parse error"))
+    }
+
+    pub fn raw(&self) -> Vec<u8> {
+        let mut raw: Vec<u8> = Vec::new();
+        self.write(&mut raw);
+        raw
+    }
+
+    fn parse(r: &mut utils::Reader) -> io::Result<Self> {
+        let kind = match r.take(1)?[0] {
+            0 => tree::EntryKind::File,
+            1 => tree::EntryKind::Dir,
+            2 => tree::EntryKind::Symlink,
+            _ => return Err(r.error()),
+        };
+        let size = u64::from_be_bytes(r.array()?);
+        let mode = u32::from_be_bytes(r.array()?);
+        let mtime = i64::from_be_bytes(r.array()?);
+        let digest = if r.take(1)?[0] != 0 {
+            Some(r.array::<[u8; 32]>()?)
+        } else {
+            None
+        };
+        let name_length = u16::from_be_bytes(r.array()?) as usize;
+        let name = r.string(name_length)?;
+        Ok(Self {
+            kind: kind,
+            name: name,
+            size: size,
+            mode: mode,
+            mtime: mtime,
+            digest: digest,
+        })
+    }
+
+    fn write(&self, raw: &mut Vec<u8>) {
+        raw.push(self.kind as u8);
+        raw.extend_from_slice(&self.size.to_be_bytes());
+        raw.extend_from_slice(&self.mode.to_be_bytes());
+        raw.extend_from_slice(&self.mtime.to_be_bytes());
+        match self.digest {
+            Some(digest) => {
+                raw.push(1);
+                raw.extend_from_slice(&digest);
+            },
+            None => raw.push(0),
+        }
+        raw.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        raw.extend_from_slice(self.name.as_bytes());
+    }
+}
+
+// the entries of `dir` sorted by name, symlinks are listed as such
+pub fn list(dir: &Path) -> io::Result<Vec<u8>> {
+    let mut stats: Vec<Stat> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let m = entry.path().symlink_metadata()?;
+        let kind = if m.file_type().is_symlink() {
+            tree::EntryKind::Symlink
+        } else if m.is_dir() {
+            tree::EntryKind::Dir
+        } else {
+            tree::EntryKind::File
+        };
+        stats.push(Stat {
+            kind: kind,
+            name: entry.file_name().to_string_lossy().into_owned(),
+            size: m.len(),
+            mode: m.mode() & 0o7777, // the kind travels on its own
+            mtime: m.mtime(),
+            digest: None,
+        });
+    }
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut raw: Vec<u8> = Vec::new();
+    raw.extend_from_slice(&(stats.len() as u32).to_be_bytes());
+    for stat in stats.iter() {
+        stat.write(&mut raw);
+    }
+    Ok(raw)
+}
+
+pub fn listing_from_raw(raw: &[u8]) -> io::Result<Vec<Stat>> {
+    let mut r = utils::Reader::new(raw, "stat parse error");
+    let count = u32::from_be_bytes(r.array()?) as usize;
+    let mut stats: Vec<Stat> = Vec::new();
+    for _ in 0..count {
+        stats.push(Stat::parse(&mut r)?);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{
+        self as unix_fs,
+        PermissionsExt,
+    };
+
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let dir = std::env::temp_dir().join(format!("robust-remote-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("d")).unwrap();
+        fs::write(dir.join("f"), b"abc").unwrap();
+        fs::set_permissions(dir.join("f"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(dir.join("d"), fs::Permissions::from_mode(0o1750)).unwrap();
+        unix_fs::symlink("f", dir.join("l")).unwrap();
+
+        let stats = listing_from_raw(&list(&dir).unwrap()).unwrap();
+        let listed: Vec<(&str, tree::EntryKind, u32)> = stats.iter().map(|s| (s.name.as_str(), s.kind, s.mode)).collect();
+        assert_eq!(listed, vec![
+            ("d", tree::EntryKind::Dir, 0o1750),
+            ("f", tree::EntryKind::File, 0o640),
+            ("l", tree::EntryKind::Symlink, 0o777),
+        ]);
+        assert_eq!(stats[1].size, 3);
+        assert!(stats.iter().all(|s| s.digest.is_none()));
+
+        let stat = Stat::from_path(&dir.join("l"), "l").unwrap();
+        assert_eq!((stat.kind, stat.mode, stat.size), (tree::EntryKind::File, 0o640, 3));
+        assert_eq!(stat.digest.unwrap()[..], Sha256::digest(b"abc")[..]);
+        assert_eq!(Stat::from_raw(&stat.raw()).unwrap(), stat);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let raw = Stat::from_path(&std::env::temp_dir(), "t").unwrap().raw();
+        for length in 0..raw.len() {
+            assert!(Stat::from_raw(&raw[..length]).is_err());
+        }
+    }
+}
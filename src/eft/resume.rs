@@ -0,0 +1,277 @@
+use std::{
+    fs::{
+        self, File, OpenOptions,
+    },
+    io::{
+        self,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    time,
+};
+
+use pnet::util::MacAddr;
+use sha2::{
+    Digest, Sha256,
+};
+
+use super::meta;
+
+// Sidecar file layout (all integers are big endian):
+//
+//   header: "EFTP" | src mac (6) | fileid (2) | identity (32)
+//   record: offset (2) | packet type (1) | length (2) | data (length)
+//
+// Records (Data, DataEnd and Meta) are appended before the packet is acknowledged,
+// so everything the sender has seen acknowledged survives a restart of the receiver.
+// The identity of the file, see `identity`, is zero until its Meta arrives; records
+// of an unidentified file are dropped on open, and those of another file than the
+// one the sender announces are dropped by `confirm`.
+
+const MAGIC: &[u8; 4] = b"EFTP";
+const IDENTITY_POSITION: usize = 12;
+const HEADER_LENGTH: usize = IDENTITY_POSITION + 32;
+const RECORD_HEADER_LENGTH: usize = 5;
+
+pub struct Fragment {
+    pub offset: u16,
+    pub packet_type: u8,
+    pub data: Vec<u8>,
+}
+
+pub struct Sidecar {
+    path: String,
+    f: File,
+    header: [u8; HEADER_LENGTH],
+}
+
+// tells apart files sent on the same fileid: the size, mtime and digest of their Meta
+pub fn identity(metadata: &meta::Metadata) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(metadata.size.to_be_bytes());
+    match metadata.mtime.duration_since(time::UNIX_EPOCH) {
+        Ok(d) => {
+            hasher.update([0]);
+            hasher.update(d.as_secs().to_be_bytes());
+            hasher.update(d.subsec_nanos().to_be_bytes());
+        },
+        Err(e) => {
+            hasher.update([1]);
+            hasher.update(e.duration().as_secs().to_be_bytes());
+            hasher.update(e.duration().subsec_nanos().to_be_bytes());
+        },
+    }
+    match metadata.digest {
+        Some(digest) => {
+            hasher.update([1]);
+            hasher.update(digest);
+        },
+        None => hasher.update([0]),
+    }
+    let mut identity = [0; 32];
+    identity.copy_from_slice(&hasher.finalize());
+    identity
+}
+
+impl Sidecar {
+    pub fn path_for(filepath: &str) -> String {
+        format!("{}.eftpart", filepath)
+    }
+
+    // opens (or creates) the sidecar of `filepath` and returns the fragments it already holds
+    pub fn open(filepath: &str, src: MacAddr, fileid: u16) -> io::Result<(Self, Vec<Fragment>)> {
+        let path = Self::path_for(filepath);
+        let mut f = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+
+        let mut raw: Vec<u8> = Vec::new();
+        f.read_to_end(&mut raw)?;
+
+        let mut header = Self::header(src, fileid, [0; 32]);
+        let mut fragments: Vec<Fragment> = Vec::new();
+        let mut valid = HEADER_LENGTH;
+        let identified = raw.len() >= HEADER_LENGTH
+            && raw[..IDENTITY_POSITION] == header[..IDENTITY_POSITION]
+            && raw[IDENTITY_POSITION..HEADER_LENGTH] != header[IDENTITY_POSITION..];
+        if identified {
+            header.copy_from_slice(&raw[..HEADER_LENGTH]);
+            while raw.len() >= valid + RECORD_HEADER_LENGTH {
+                let offset = u16::from_be_bytes([raw[valid], raw[valid + 1]]);
+                let packet_type = raw[valid + 2];
+                let length = u16::from_be_bytes([raw[valid + 3], raw[valid + 4]]) as usize;
+                let start = valid + RECORD_HEADER_LENGTH;
+                if raw.len() < start + length { // torn write
+                    break;
+                }
+                fragments.push(Fragment {
+                    offset: offset,
+                    packet_type: packet_type,
+                    data: raw[start..start + length].to_vec(),
+                });
+                valid = start + length;
+            }
+        } else { // missing, foreign, unidentified or corrupted sidecar
+            f.set_len(0)?;
+            f.seek(SeekFrom::Start(0))?;
+            f.write_all(&header)?;
+        }
+        f.set_len(valid as u64)?;
+        f.seek(SeekFrom::End(0))?;
+
+        Ok((
+            Self {
+                path: path,
+                f: f,
+                header: header,
+            },
+            fragments,
+        ))
+    }
+
+    pub fn append(&mut self, offset: u16, packet_type: u8, data: &[u8]) -> io::Result<()> {
+        let mut record: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LENGTH + data.len());
+        record.extend_from_slice(&offset.to_be_bytes());
+        record.push(packet_type);
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        self.f.write_all(&record)?;
+        // on disk before the fragment is acknowledged, or a crash could lose it for good
+        self.f.sync_data()
+    }
+
+    // records `identity` as the file's, true if the records held are of that file.
+    // otherwise they are dropped, and false tells the caller to drop its copy too
+    pub fn confirm(&mut self, identity: [u8; 32]) -> io::Result<bool> {
+        if self.header[IDENTITY_POSITION..] == identity[..] {
+            return Ok(true);
+        }
+        // records taken before the Meta are of the file it announces
+        let unknown = self.header[IDENTITY_POSITION..] == [0; 32][..];
+        self.header[IDENTITY_POSITION..].copy_from_slice(&identity);
+        if !unknown {
+            self.f.set_len(0)?;
+        }
+        self.f.seek(SeekFrom::Start(0))?;
+        self.f.write_all(&self.header)?;
+        self.f.seek(SeekFrom::End(0))?;
+        self.f.sync_data()?;
+        Ok(unknown)
+    }
+
+    pub fn remove(self) -> io::Result<()> {
+        drop(self.f);
+        fs::remove_file(&self.path)
+    }
+
+    fn header(src: MacAddr, fileid: u16, identity: [u8; 32]) -> [u8; HEADER_LENGTH] {
+        let mut header = [0; HEADER_LENGTH];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..10].copy_from_slice(&[src.0, src.1, src.2, src.3, src.4, src.5]);
+        header[10..IDENTITY_POSITION].copy_from_slice(&fileid.to_be_bytes());
+        header[IDENTITY_POSITION..].copy_from_slice(&identity);
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: MacAddr = MacAddr(2, 0, 0, 0, 0, 1);
+
+    // a file path of its own for each test, without a sidecar
+    fn filepath(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("robust-resume-{}-{}", std::process::id(), name));
+        fs::remove_file(Sidecar::path_for(path.to_str().unwrap())).ok();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn metadata(size: u64) -> meta::Metadata {
+        meta::Metadata {
+            path: String::from("a"),
+            size: size,
+            mode: 0o644,
+            mtime: time::UNIX_EPOCH + time::Duration::from_secs(1_700_000_000),
+            xattrs: Vec::new(),
+            compression: None,
+            digest: None,
+        }
+    }
+
+    // a sidecar of file `size` holding Meta and two fragments
+    fn filled(filepath: &str, size: u64) -> Sidecar {
+        let (mut sidecar, fragments) = Sidecar::open(filepath, SRC, 7).unwrap();
+        assert!(fragments.is_empty());
+        assert!(sidecar.confirm(identity(&metadata(size))).unwrap());
+        sidecar.append(0, 5, &metadata(size).raw().unwrap()).unwrap();
+        sidecar.append(0, 1, &[1, 2, 3]).unwrap();
+        sidecar.append(1, 2, &[4]).unwrap();
+        sidecar
+    }
+
+    #[test]
+    fn replay() {
+        let filepath = filepath("replay");
+        drop(filled(&filepath, 4));
+        let (mut sidecar, fragments) = Sidecar::open(&filepath, SRC, 7).unwrap();
+        let replayed: Vec<(u16, u8, Vec<u8>)> = fragments.into_iter().map(|f| (f.offset, f.packet_type, f.data)).collect();
+        assert_eq!(replayed[0], (0, 5, metadata(4).raw().unwrap()));
+        assert_eq!(replayed[1..], [(0, 1, vec![1, 2, 3]), (1, 2, vec![4])]);
+        assert!(sidecar.confirm(identity(&metadata(4))).unwrap());
+        sidecar.remove().unwrap();
+    }
+
+    #[test]
+    fn torn_record() {
+        let filepath = filepath("torn");
+        drop(filled(&filepath, 4));
+        let path = Sidecar::path_for(&filepath);
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 1).unwrap();
+        let (mut sidecar, fragments) = Sidecar::open(&filepath, SRC, 7).unwrap();
+        assert_eq!(fragments.len(), 2);
+        // the torn record is cut off, so the next one follows the last whole record
+        sidecar.append(1, 2, &[4]).unwrap();
+        drop(sidecar);
+        let (sidecar, fragments) = Sidecar::open(&filepath, SRC, 7).unwrap();
+        assert_eq!(fragments.last().map(|f| (f.offset, f.data.clone())), Some((1, vec![4])));
+        sidecar.remove().unwrap();
+    }
+
+    #[test]
+    fn foreign_header() {
+        let filepath = filepath("foreign");
+        drop(filled(&filepath, 4));
+        assert!(Sidecar::open(&filepath, MacAddr(2, 0, 0, 0, 0, 2), 7).unwrap().1.is_empty());
+        drop(filled(&filepath, 4));
+        assert!(Sidecar::open(&filepath, SRC, 8).unwrap().1.is_empty());
+        fs::remove_file(Sidecar::path_for(&filepath)).unwrap();
+    }
+
+    #[test]
+    fn another_file() {
+        let filepath = filepath("another");
+        drop(filled(&filepath, 4));
+        let (mut sidecar, fragments) = Sidecar::open(&filepath, SRC, 7).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(!sidecar.confirm(identity(&metadata(5))).unwrap());
+        sidecar.append(0, 2, &[9]).unwrap();
+        drop(sidecar);
+        let (mut sidecar, fragments) = Sidecar::open(&filepath, SRC, 7).unwrap();
+        assert_eq!(fragments.iter().map(|f| f.data.clone()).collect::<Vec<_>>(), vec![vec![9]]);
+        assert!(sidecar.confirm(identity(&metadata(5))).unwrap());
+        sidecar.remove().unwrap();
+    }
+
+    #[test]
+    fn unidentified() {
+        let filepath = filepath("unidentified");
+        let (mut sidecar, _) = Sidecar::open(&filepath, SRC, 7).unwrap();
+        sidecar.append(0, 1, &[1]).unwrap();
+        drop(sidecar);
+        let (sidecar, fragments) = Sidecar::open(&filepath, SRC, 7).unwrap();
+        assert!(fragments.is_empty());
+        sidecar.remove().unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    io,
+    mem,
+    ops::Range,
+    ptr,
+    sync::atomic::{
+        fence, Ordering,
+    },
+    thread,
+    time,
+};
+
+use pnet::datalink::{
+    DataLinkReceiver, DataLinkSender, NetworkInterface,
+};
+
+// Datapath on memory mapped AF_PACKET rings (TPACKET_V3, Linux only).
+//
+// Received frames are handed out in place from the RX ring, block by block, and
+// a block is returned to the kernel once every frame in it was consumed. Frames
+// are built in place in the TX ring and a whole build_and_send batch is submitted
+// with a single send(2). The TX socket uses protocol 0 so that it never queues
+// received frames.
+//
+// When the driver drops a frame (e.g. a veth peer with a smaller MTU) the kernel
+// hands it back at the head of the TX ring and retries it on every send(2), which
+// would wedge the ring. A frame that keeps failing there after send(2) returned is
+// made malformed, and PACKET_LOSS lets the kernel skip it like any other lost packet.
+// Frames behind it, and frames of a send(2) that failed for any other reason, stay
+// queued for the next one.
+
+// from <linux/if_packet.h>
+const SOL_PACKET: libc::c_int = 263;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_TX_RING: libc::c_int = 13;
+const PACKET_LOSS: libc::c_int = 14;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1;
+const TP_STATUS_SENDING: u32 = 2;
+const ETH_P_ALL: u16 = 0x0003;
+
+// TPACKET_ALIGN(sizeof(struct tpacket3_hdr)), where transmitted frames start
+const TX_DATA_OFFSET: usize = 48;
+
+const BLOCK_SIZE: u32 = 1 << 20;
+const RX_BLOCKS: u32 = 16;
+const TX_BLOCKS: u32 = 4;
+// a tagged jumbo frame and the tpacket header fit in one frame
+const FRAME_SIZE: u32 = 1 << 14;
+// milliseconds until the kernel hands over a partially filled block
+const BLOCK_TIMEOUT: u32 = 1;
+// send(2) attempts before the frame at the head of the TX ring is given up on
+const TX_ATTEMPTS: usize = 3;
+// microseconds between them
+const TX_RETRY_INTERVAL: u64 = 200;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+// struct tpacket_block_desc with its tpacket_hdr_v1, up to the fields we use
+#[repr(C)]
+struct BlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: u32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+}
+
+// struct tpacket3_hdr, up to the fields we use
+#[repr(C)]
+struct Tpacket3Hdr {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+}
+
+struct Socket {
+    fd: libc::c_int,
+    map: *mut u8,
+    map_length: usize,
+}
+
+// the mapping is only touched through the RingSender or RingReceiver owning it
+unsafe impl Send for Socket {}
+
+impl Socket {
+    fn open(interface: &NetworkInterface, protocol: u16, ring: libc::c_int, blocks: u32, block_timeout: u32) -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol.to_be() as libc::c_int) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut socket = Self {
+            fd: fd,
+            map: ptr::null_mut(),
+            map_length: 0,
+        };
+
+        socket.setsockopt(PACKET_VERSION, &TPACKET_V3)?;
+        let req = TpacketReq3 {
+            tp_block_size: BLOCK_SIZE,
+            tp_block_nr: blocks,
+            tp_frame_size: FRAME_SIZE,
+            tp_frame_nr: BLOCK_SIZE / FRAME_SIZE * blocks,
+            tp_retire_blk_tov: block_timeout,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        if ring == PACKET_TX_RING { // must precede the ring setup
+            socket.setsockopt(PACKET_LOSS, &(1 as libc::c_int))?;
+        }
+        socket.setsockopt(ring, &req)?;
+
+        let map_length = (BLOCK_SIZE * blocks) as usize;
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), map_length, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0)
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        socket.map = map as *mut u8;
+        socket.map_length = map_length;
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol.to_be();
+        addr.sll_ifindex = interface.index as i32;
+        let r = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_ll as *const libc::sockaddr, mem::size_of::<libc::sockaddr_ll>() as u32)
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    fn setsockopt<T>(&self, option: libc::c_int, value: &T) -> io::Result<()> {
+        let r = unsafe {
+            libc::setsockopt(self.fd, SOL_PACKET, option, value as *const T as *const libc::c_void, mem::size_of::<T>() as u32)
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn poll(&self, events: libc::c_short, timeout: Option<time::Duration>) -> io::Result<bool> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: events,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |t| t.as_millis() as libc::c_int);
+        let r = unsafe { libc::poll(&mut pfd, 1, timeout) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(r > 0)
+    }
+
+    // the status word the kernel and we hand a block or frame back and forth with
+    fn status(&self, offset: usize) -> *mut u32 {
+        unsafe { self.map.add(offset) as *mut u32 }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.map.is_null() {
+                libc::munmap(self.map as *mut libc::c_void, self.map_length);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+pub struct RingSender {
+    socket: Socket,
+    frame: usize,
+    frames: usize,
+}
+
+impl RingSender {
+    // submits every frame marked for sending and waits until the kernel took them
+    fn flush(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        let mut attempts = 0;
+        loop {
+            let head = self.oldest_request();
+            if unsafe { libc::send(self.socket.fd, ptr::null(), 0, 0) } >= 0 {
+                return result;
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // the channel itself failed, nothing is retried
+                Some(libc::ENETDOWN) | Some(libc::ENODEV) | Some(libc::ENXIO) | Some(libc::EBADF) => return Err(e),
+                _ => (),
+            }
+            if result.is_ok() {
+                result = Err(e);
+            }
+            // only a frame the kernel handed back without getting any further counts as stuck
+            let stuck = match self.oldest_request() {
+                Some(frame) if Some(frame) == head => frame,
+                Some(_) => {
+                    attempts = 0;
+                    continue;
+                },
+                None => return result,
+            };
+            attempts += 1;
+            if attempts < TX_ATTEMPTS {
+                thread::sleep(time::Duration::from_micros(TX_RETRY_INTERVAL));
+                continue;
+            }
+            attempts = 0;
+            unsafe {
+                // shorter than an ethernet header
+                (*(self.socket.map.add(stuck * FRAME_SIZE as usize) as *mut Tpacket3Hdr)).tp_len = 0;
+            }
+        }
+    }
+
+    // the frame the kernel is working on: the oldest one still marked for sending
+    fn oldest_request(&self) -> Option<usize> {
+        (0..self.frames)
+            .map(|i| (self.frame + i) % self.frames)
+            .find(|frame| unsafe { ptr::read_volatile(self.frame_status(*frame)) } == TP_STATUS_SEND_REQUEST)
+    }
+
+    fn frame_status(&self, frame: usize) -> *mut u32 {
+        self.socket.status(frame * FRAME_SIZE as usize + mem::size_of::<u32>() * 5)
+    }
+
+    fn next_frame(&mut self) -> io::Result<*mut u8> {
+        let offset = self.frame * FRAME_SIZE as usize;
+        let status = self.frame_status(self.frame);
+        loop {
+            let s = unsafe { ptr::read_volatile(status) };
+            if s == TP_STATUS_AVAILABLE {
+                break;
+            }
+            if s & (TP_STATUS_SEND_REQUEST | TP_STATUS_SENDING) == 0 {
+                // a frame the kernel rejected, e.g. TP_STATUS_WRONG_FORMAT
+                unsafe { ptr::write_volatile(status, TP_STATUS_AVAILABLE) };
+                break;
+            }
+            self.flush()?;
+        }
+        self.frame = (self.frame + 1) % self.frames;
+        Ok(unsafe { self.socket.map.add(offset) })
+    }
+}
+
+impl DataLinkSender for RingSender {
+    fn build_and_send(&mut self, num_packets: usize, packet_size: usize, func: &mut dyn FnMut(&mut [u8])) -> Option<io::Result<()>> {
+        if TX_DATA_OFFSET + packet_size > FRAME_SIZE as usize {
+            return None;
+        }
+        for _ in 0..num_packets {
+            let frame = match self.next_frame() {
+                Ok(frame) => frame,
+                Err(e) => return Some(Err(e)),
+            };
+            unsafe {
+                func(std::slice::from_raw_parts_mut(frame.add(TX_DATA_OFFSET), packet_size));
+                let hdr = frame as *mut Tpacket3Hdr;
+                (*hdr).tp_next_offset = 0;
+                (*hdr).tp_len = packet_size as u32;
+                (*hdr).tp_snaplen = packet_size as u32;
+                fence(Ordering::Release);
+                ptr::write_volatile(&mut (*hdr).tp_status, TP_STATUS_SEND_REQUEST);
+            }
+        }
+        Some(self.flush())
+    }
+
+    fn send_to(&mut self, packet: &[u8], _dst: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        self.build_and_send(1, packet.len(), &mut |frame| frame.copy_from_slice(packet))
+    }
+}
+
+pub struct RingReceiver {
+    socket: Socket,
+    read_timeout: Option<time::Duration>,
+    block: usize,
+    blocks: usize,
+    packet: usize, // offset of the next frame within the block
+    remaining: u32, // frames of the current block not handed out yet
+    held: bool, // the current block belongs to us
+}
+
+// where the frame whose header is `packet` bytes into a block lies in that block, as told
+// by the kernel. None if the header or the frame would run past the end of the block
+fn frame_range(packet: usize, mac: u16, snaplen: u32) -> Option<Range<usize>> {
+    let block_size = BLOCK_SIZE as usize;
+    if packet.checked_add(mem::size_of::<Tpacket3Hdr>()).map_or(true, |end| end > block_size) {
+        return None;
+    }
+    let start = packet + mac as usize;
+    let end = start.checked_add(snaplen as usize)?;
+    if end > block_size {
+        return None;
+    }
+    Some(start..end)
+}
+
+impl RingReceiver {
+    fn release(&mut self) {
+        if self.held {
+            fence(Ordering::Release);
+            unsafe { ptr::write_volatile(self.socket.status(self.block * BLOCK_SIZE as usize + 8), TP_STATUS_KERNEL) };
+            self.held = false;
+            self.block = (self.block + 1) % self.blocks;
+        }
+    }
+}
+
+impl DataLinkReceiver for RingReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        // the previous frame is no longer borrowed, so its block may go back once exhausted
+        if self.held && self.remaining == 0 {
+            self.release();
+        }
+        while !self.held {
+            let base = self.block * BLOCK_SIZE as usize;
+            let status = unsafe { ptr::read_volatile(self.socket.status(base + 8)) };
+            if status & TP_STATUS_USER == 0 {
+                if !self.socket.poll(libc::POLLIN | libc::POLLERR, self.read_timeout)? {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+                }
+                continue;
+            }
+            fence(Ordering::Acquire);
+            let desc = unsafe { &*(self.socket.map.add(base) as *const BlockDesc) };
+            self.held = true;
+            self.remaining = desc.num_pkts;
+            self.packet = desc.offset_to_first_pkt as usize;
+            if self.remaining == 0 {
+                self.release();
+            }
+        }
+
+        let base = self.block * BLOCK_SIZE as usize;
+        let invalid = |receiver: &mut Self| {
+            // the rest of the block cannot be trusted either
+            receiver.remaining = 0;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "frame past the end of its block"))
+        };
+        // the header must fit before it is read
+        if frame_range(self.packet, 0, 0).is_none() {
+            return invalid(self);
+        }
+        let hdr = unsafe { &*(self.socket.map.add(base + self.packet) as *const Tpacket3Hdr) };
+        let range = match frame_range(self.packet, hdr.tp_mac, hdr.tp_snaplen) {
+            Some(range) => range,
+            None => return invalid(self),
+        };
+        self.packet += hdr.tp_next_offset as usize;
+        self.remaining -= 1;
+        Ok(unsafe { std::slice::from_raw_parts(self.socket.map.add(base + range.start), range.len()) })
+    }
+}
+
+pub fn channel(interface: &NetworkInterface, read_timeout: Option<time::Duration>) -> io::Result<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)> {
+    let tx = Socket::open(interface, 0, PACKET_TX_RING, TX_BLOCKS, 0)?;
+    let rx = Socket::open(interface, ETH_P_ALL, PACKET_RX_RING, RX_BLOCKS, BLOCK_TIMEOUT)?;
+    Ok((
+        Box::new(RingSender {
+            socket: tx,
+            frame: 0,
+            frames: (BLOCK_SIZE / FRAME_SIZE * TX_BLOCKS) as usize,
+        }),
+        Box::new(RingReceiver {
+            socket: rx,
+            read_timeout: read_timeout,
+            block: 0,
+            blocks: RX_BLOCKS as usize,
+            packet: 0,
+            remaining: 0,
+            held: false,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_in_block() {
+        let block_size = BLOCK_SIZE as usize;
+        let header = mem::size_of::<Tpacket3Hdr>();
+        assert_eq!(frame_range(48, 66, 1514), Some(114..1628));
+        // the last byte of the block
+        assert_eq!(frame_range(block_size - 100, 90, 10), Some(block_size - 10..block_size));
+        assert_eq!(frame_range(block_size - 100, 90, 11), None);
+        assert_eq!(frame_range(block_size - header, 0, 0), Some(block_size - header..block_size - header));
+        assert_eq!(frame_range(block_size - header + 1, 0, 0), None);
+        assert_eq!(frame_range(usize::MAX, 0, 0), None);
+        assert_eq!(frame_range(48, u16::MAX, u32::MAX), None);
+    }
+
+    #[test]
+    fn ring_geometry() {
+        // whole frames tile every block, so TX frame n starts at n * FRAME_SIZE
+        assert_eq!(BLOCK_SIZE % FRAME_SIZE, 0);
+        let frames = (BLOCK_SIZE / FRAME_SIZE * TX_BLOCKS) as usize;
+        assert_eq!(frames * FRAME_SIZE as usize, (BLOCK_SIZE * TX_BLOCKS) as usize);
+        // the status word of a TX frame is within its header
+        let hdr: Tpacket3Hdr = unsafe { mem::zeroed() };
+        let status = &hdr.tp_status as *const u32 as usize - &hdr as *const Tpacket3Hdr as usize;
+        assert_eq!(status, mem::size_of::<u32>() * 5);
+        assert!(mem::size_of::<Tpacket3Hdr>() <= TX_DATA_OFFSET);
+        // and a tagged jumbo frame, ethernet header and 802.1Q tag included, fits after it
+        assert!(TX_DATA_OFFSET + 14 + 4 + crate::general::MAX_MTU <= FRAME_SIZE as usize);
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::{
+    BTreeMap, hash_map::Entry, HashMap, VecDeque,
+};
+
+use pnet::util::MacAddr;
+
+use super::Tri;
+use crate::general;
+
+// Picks the next fragment to (re)transmit. Every priority class is served
+// strictly before the lower ones; within a class, deficit round-robin runs first
+// across peers and then across the files of each peer, with the packet length as
+// the cost. A peer with a hundred transfers thus gets the same share of the link
+// as a peer with one, and a large file cannot hold back the others. Peers that
+// are not admitted, e.g. out of tokens, are passed over without losing their turn.
+
+// bytes granted per round, enough for any packet
+const QUANTUM: usize = general::MAX_MTU + general::FEC_HEADER_LENGTH + general::AEAD_OVERHEAD;
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Bulk = 0,
+    Normal = 1,
+    Urgent = 2,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+#[derive(Default)]
+struct File {
+    deficit: usize,
+    due: BTreeMap<u16, usize>, // offset -> cost
+}
+
+#[derive(Default)]
+struct Peer {
+    deficit: usize,
+    files: HashMap<u16, File>,
+    order: VecDeque<u16>,
+}
+
+impl Peer {
+    // the fragment this peer sends next, rotating files whose deficit is spent
+    fn head(&mut self) -> Option<(u16, u16, usize)> {
+        loop {
+            let fileid = *self.order.front()?;
+            let file = self.files.get_mut(&fileid).unwrap();
+            let (offset, cost) = match file.due.iter().next() {
+                Some((offset, cost)) => (*offset, *cost),
+                None => {
+                    self.files.remove(&fileid);
+                    self.order.pop_front();
+                    continue;
+                },
+            };
+            if cost <= file.deficit {
+                return Some((fileid, offset, cost));
+            }
+            file.deficit += QUANTUM;
+            self.order.rotate_left(1);
+        }
+    }
+
+    fn take(&mut self, fileid: u16, offset: u16, cost: usize) {
+        let file = self.files.get_mut(&fileid).unwrap();
+        file.due.remove(&offset);
+        file.deficit -= cost;
+        if file.due.is_empty() {
+            self.files.remove(&fileid);
+            self.order.pop_front();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Class {
+    peers: HashMap<(MacAddr, MacAddr), Peer>,
+    order: VecDeque<(MacAddr, MacAddr)>,
+}
+
+impl Class {
+    fn next<F: FnMut(&Tri, usize) -> bool>(&mut self, admit: &mut F) -> Option<(Tri, u16)> {
+        let mut skipped = 0;
+        loop {
+            if skipped >= self.order.len() {
+                return None;
+            }
+            let endpoint = *self.order.front()?;
+            let peer = self.peers.get_mut(&endpoint).unwrap();
+            let (fileid, offset, cost) = match peer.head() {
+                Some(head) => head,
+                None => {
+                    self.peers.remove(&endpoint);
+                    self.order.pop_front();
+                    continue;
+                },
+            };
+            if cost <= peer.deficit {
+                let tri = Tri { src: endpoint.0, dst: endpoint.1, fileid: fileid, };
+                if !admit(&tri, cost) {
+                    skipped += 1;
+                    self.order.rotate_left(1);
+                    continue;
+                }
+                peer.deficit -= cost;
+                peer.take(fileid, offset, cost);
+                if peer.files.is_empty() {
+                    self.peers.remove(&endpoint);
+                    self.order.pop_front();
+                }
+                return Some((tri, offset));
+            }
+            peer.deficit += QUANTUM;
+            self.order.rotate_left(1);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    classes: [Class; 3], // indexed by priority
+}
+
+impl Scheduler {
+    // marks `offset` of `tri` due, costing `cost` bytes on the wire
+    pub fn push(&mut self, tri: Tri, priority: Priority, offset: u16, cost: usize) {
+        // newcomers join at the back of the round with a full quantum
+        let class = &mut self.classes[priority as usize];
+        let endpoint = (tri.src, tri.dst);
+        let peer = match class.peers.entry(endpoint) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                class.order.push_back(endpoint);
+                e.insert(Peer { deficit: QUANTUM, ..Default::default() })
+            },
+        };
+        let file = match peer.files.entry(tri.fileid) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                peer.order.push_back(tri.fileid);
+                e.insert(File { deficit: QUANTUM, ..Default::default() })
+            },
+        };
+        file.due.insert(offset, cost);
+    }
+
+    // the next fragment that `admit` lets go, charging its cost
+    pub fn next<F: FnMut(&Tri, usize) -> bool>(&mut self, mut admit: F) -> Option<(Tri, u16)> {
+        self.classes.iter_mut().rev().find_map(|class| class.next(&mut admit))
+    }
+
+    // drops transfers for which `keep` is false
+    pub fn retain<F: FnMut(&Tri) -> bool>(&mut self, mut keep: F) {
+        for class in self.classes.iter_mut() {
+            for (endpoint, peer) in class.peers.iter_mut() {
+                peer.files.retain(|fileid, _| keep(&Tri { src: endpoint.0, dst: endpoint.1, fileid: *fileid, }));
+                let files = &peer.files;
+                peer.order.retain(|fileid| files.contains_key(fileid));
+            }
+            class.peers.retain(|_, peer| !peer.files.is_empty());
+            let peers = &class.peers;
+            class.order.retain(|endpoint| peers.contains_key(endpoint));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tri(peer: u8, fileid: u16) -> Tri {
+        Tri { src: MacAddr(2, 0, 0, 0, 0, 1), dst: MacAddr(2, 0, 0, 0, 1, peer), fileid: fileid, }
+    }
+
+    // queues `count` fragments of `cost` bytes for `tri`
+    fn push(s: &mut Scheduler, tri: Tri, priority: Priority, count: u16, cost: usize) {
+        for offset in 0..count {
+            s.push(tri, priority, offset, cost);
+        }
+    }
+
+    // the bytes each (peer, fileid) gets out of the first `picks` fragments
+    fn drain(s: &mut Scheduler, costs: &HashMap<Tri, usize>, picks: usize) -> HashMap<Tri, usize> {
+        let mut sent: HashMap<Tri, usize> = HashMap::new();
+        for _ in 0..picks {
+            let (tri, _) = s.next(|_, _| true).unwrap();
+            *sent.entry(tri).or_insert(0) += costs[&tri];
+        }
+        sent
+    }
+
+    #[test]
+    fn fair_peers() {
+        // one peer with a single file of large fragments, another with ten files of small ones
+        let mut s = Scheduler::default();
+        let mut costs: HashMap<Tri, usize> = HashMap::new();
+        push(&mut s, tri(1, 0), Priority::Normal, 200, 9000);
+        costs.insert(tri(1, 0), 9000);
+        for fileid in 0..10 {
+            push(&mut s, tri(2, fileid), Priority::Normal, 200, 300);
+            costs.insert(tri(2, fileid), 300);
+        }
+        let sent = drain(&mut s, &costs, 1000);
+        let first: usize = sent.iter().filter(|(t, _)| t.dst == tri(1, 0).dst).map(|(_, b)| b).sum();
+        let second: usize = sent.iter().filter(|(t, _)| t.dst == tri(2, 0).dst).map(|(_, b)| b).sum();
+        assert!(first.max(second) - first.min(second) <= QUANTUM, "{} {}", first, second);
+    }
+
+    #[test]
+    fn fair_files() {
+        let mut s = Scheduler::default();
+        let mut costs: HashMap<Tri, usize> = HashMap::new();
+        for (fileid, cost) in [(0, 9000), (1, 1500), (2, 500)].iter() {
+            push(&mut s, tri(1, *fileid), Priority::Normal, 1000, *cost);
+            costs.insert(tri(1, *fileid), *cost);
+        }
+        let sent = drain(&mut s, &costs, 600);
+        let bytes: Vec<usize> = (0..3).map(|fileid| sent[&tri(1, fileid)]).collect();
+        let (least, most) = (*bytes.iter().min().unwrap(), *bytes.iter().max().unwrap());
+        assert!(most - least <= QUANTUM, "{:?}", bytes);
+    }
+
+    #[test]
+    fn strict_priority() {
+        let mut s = Scheduler::default();
+        push(&mut s, tri(1, 0), Priority::Bulk, 3, 100);
+        push(&mut s, tri(2, 0), Priority::Normal, 3, 100);
+        let mut order: Vec<Priority> = Vec::new();
+        let class = |tri: Tri| match tri.dst.5 {
+            1 => Priority::Bulk,
+            2 => Priority::Normal,
+            _ => Priority::Urgent,
+        };
+        order.push(class(s.next(|_, _| true).unwrap().0));
+        // a class above those queued goes first from the moment it has work
+        push(&mut s, tri(3, 0), Priority::Urgent, 2, 100);
+        while let Some((tri, _)) = s.next(|_, _| true) {
+            order.push(class(tri));
+        }
+        assert_eq!(order, vec![
+            Priority::Normal,
+            Priority::Urgent, Priority::Urgent,
+            Priority::Normal, Priority::Normal,
+            Priority::Bulk, Priority::Bulk, Priority::Bulk,
+        ]);
+    }
+
+    #[test]
+    fn admit() {
+        let mut s = Scheduler::default();
+        push(&mut s, tri(1, 0), Priority::Normal, 2, 100);
+        push(&mut s, tri(2, 0), Priority::Normal, 2, 100);
+        push(&mut s, tri(3, 0), Priority::Bulk, 1, 100);
+
+        // refused peers are passed over, down to the lower classes
+        let mut asked: Vec<u8> = Vec::new();
+        let picked = s.next(|tri, _| {
+            asked.push(tri.dst.5);
+            tri.dst.5 == 3
+        });
+        assert_eq!(asked, vec![1, 2, 3]);
+        assert_eq!(picked.map(|(tri, _)| tri), Some(tri(3, 0)));
+        assert!(s.next(|_, _| false).is_none());
+
+        // and keep their turn: the first refused is still the first served
+        let picks: Vec<(Tri, u16)> = (0..4).map(|_| s.next(|_, _| true).unwrap()).collect();
+        assert_eq!(picks, vec![(tri(1, 0), 0), (tri(1, 0), 1), (tri(2, 0), 0), (tri(2, 0), 1)]);
+    }
+
+    #[test]
+    fn short_deficit() {
+        // a full sized packet spends the peer's quantum, so the other peer goes next
+        // and the first is not asked about a packet its deficit does not cover
+        let mut s = Scheduler::default();
+        push(&mut s, tri(1, 0), Priority::Normal, 3, QUANTUM);
+        push(&mut s, tri(2, 0), Priority::Normal, 3, 100);
+        let mut asked: Vec<(u8, usize)> = Vec::new();
+        let mut picks: Vec<u8> = Vec::new();
+        for _ in 0..4 {
+            let (tri, _) = s.next(|tri, cost| {
+                asked.push((tri.dst.5, cost));
+                true
+            }).unwrap();
+            picks.push(tri.dst.5);
+        }
+        assert_eq!(picks, vec![1, 2, 2, 2]);
+        assert_eq!(asked, vec![(1, QUANTUM), (2, 100), (2, 100), (2, 100)]);
+        // with nothing else left, the deficit is refilled rather than skipped
+        assert_eq!(s.next(|_, _| true), Some((tri(1, 0), 1)));
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::HashSet,
+    fs::{
+        self, File,
+    },
+    io::{
+        self,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    os::unix::fs::{
+        self as unix_fs,
+        MetadataExt,
+        PermissionsExt,
+    },
+    path::{
+        Component, Path, PathBuf,
+    },
+    time,
+};
+
+use crate::{
+    general,
+    utils,
+};
+use super::meta;
+
+// Manifest payload (all integers are big endian):
+//
+//   entry count (4) | entries ...
+//   entry: kind (1) | fileid (2) | sparse (1) | mode (4) | mtime secs (8)
+//          path length (2) | path | target length (2) | target
+//
+// The manifest travels as an ordinary file with the first fileid of the tree;
+// every regular file follows with its own fileid and Meta packet.
+
+const SPARSE_BLOCK: usize = 4096;
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SymlinkPolicy {
+    Skip,
+    Follow,
+    Preserve, // recreate the link itself on the receiver
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> Self {
+        SymlinkPolicy::Preserve
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EntryKind {
+    File = 0,
+    Dir = 1,
+    Symlink = 2,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub fileid: u16,
+    pub sparse: bool,
+    pub mode: u32,
+    pub mtime: i64,
+    pub path: String,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    // walks `dir` and assigns fileids from `first_fileid` on, below the reserved ones.
+    // returns the manifest and (fileid, local path, relative path) of every file
+    pub fn walk(dir: &Path, first_fileid: u16, symlinks: SymlinkPolicy) -> io::Result<(Self, Vec<(u16, PathBuf, String)>)> {
+        if first_fileid >= general::SOLICIT_FILEID {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "reserved fileid"));
+        }
+        let mut w = Walker {
+            manifest: Self::default(),
+            files: Vec::new(),
+            next_fileid: first_fileid,
+            symlinks: symlinks,
+            visited: HashSet::new(),
+        };
+        let m = fs::metadata(dir)?;
+        w.visited.insert((m.dev(), m.ino()));
+        w.walk(dir, "")?;
+        Ok((w.manifest, w.files))
+    }
+
+    pub fn from_raw(raw: &[u8]) -> io::Result<Self> {
+        let mut r = utils::Reader::new(raw, "manifest parse error");
+        let mut manifest = Self::default();
+        let count = u32::from_be_bytes(r.array()?) as usize;
+        for _ in 0..count {
+            let kind = match r.take(1)?[0] {
+                0 => EntryKind::File,
+                1 => EntryKind::Dir,
+                2 => EntryKind::Symlink,
+                _ => return Err(r.error()),
+            };
+            let fileid = u16::from_be_bytes(r.array()?);
+            let sparse = r.take(1)?[0] != 0;
+            let mode = u32::from_be_bytes(r.array()?);
+            let mtime = i64::from_be_bytes(r.array()?);
+            let path_length = u16::from_be_bytes(r.array()?) as usize;
+            let path = r.string(path_length)?;
+            let target_length = u16::from_be_bytes(r.array()?) as usize;
+            let target = r.string(target_length)?;
+            manifest.entries.push(Entry {
+                kind: kind,
+                fileid: fileid,
+                sparse: sparse,
+                mode: mode,
+                mtime: mtime,
+                path: path,
+                target: target,
+            });
+        }
+        Ok(manifest)
+    }
+
+    pub fn raw(&self) -> Vec<u8> {
+        let mut raw: Vec<u8> = Vec::new();
+        raw.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for e in self.entries.iter() {
+            raw.push(e.kind as u8);
+            raw.extend_from_slice(&e.fileid.to_be_bytes());
+            raw.push(e.sparse as u8);
+            raw.extend_from_slice(&e.mode.to_be_bytes());
+            raw.extend_from_slice(&e.mtime.to_be_bytes());
+            raw.extend_from_slice(&(e.path.len() as u16).to_be_bytes());
+            raw.extend_from_slice(e.path.as_bytes());
+            raw.extend_from_slice(&(e.target.len() as u16).to_be_bytes());
+            raw.extend_from_slice(e.target.as_bytes());
+        }
+        raw
+    }
+
+    // creates every directory under `root` before any file is written
+    pub fn create_dirs(&self, root: &Path) -> io::Result<()> {
+        for e in self.entries.iter().filter(|e| e.kind == EntryKind::Dir) {
+            let dir = meta::create_path(root, &e.path)?;
+            if fs::symlink_metadata(&dir).map_or(false, |m| m.file_type().is_symlink()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "path through a symlink"));
+            }
+            fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    // links are created after the files so that no file is ever written through one
+    pub fn create_symlinks(&self, root: &Path) -> io::Result<()> {
+        // every link of the tree, including those not created yet
+        let links: HashSet<PathBuf> = self.entries.iter()
+            .filter(|e| e.kind == EntryKind::Symlink)
+            .map(|e| relative(&e.path))
+            .collect();
+        for e in self.entries.iter().filter(|e| e.kind == EntryKind::Symlink) {
+            let link = meta::create_path(root, &e.path)?;
+            if !stays_inside(root, &links, &relative(&e.path), &e.target) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unsafe symlink target"));
+            }
+            unix_fs::symlink(&e.target, link)?;
+        }
+        Ok(())
+    }
+
+    // directory times are set last, writing their contents touches them
+    pub fn finish_dirs(&self, root: &Path) -> io::Result<()> {
+        let mut dirs: Vec<&Entry> = self.entries.iter().filter(|e| e.kind == EntryKind::Dir).collect();
+        dirs.sort_by_key(|e| std::cmp::Reverse(e.path.len()));
+        for e in dirs {
+            let dir = meta::target_path(root, &e.path)?;
+            let mtime = to_system_time(e.mtime).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid mtime")
+            })?;
+            File::open(&dir)?.set_modified(mtime)?;
+            // no setgid or sticky bits from the sender, see Metadata::apply
+            fs::set_permissions(&dir, fs::Permissions::from_mode(e.mode & 0o777))?;
+        }
+        Ok(())
+    }
+}
+
+// writes `data` leaving holes for all-zero blocks of files that were sparse on the sender
+pub fn write_file(target: &Path, data: &[u8], sparse: bool) -> io::Result<()> {
+    let mut f = meta::create_file(target)?;
+    if !sparse {
+        return f.write_all(data);
+    }
+    for block in data.chunks(SPARSE_BLOCK) {
+        if block.iter().all(|b| *b == 0) {
+            f.seek(SeekFrom::Current(block.len() as i64))?;
+        } else {
+            f.write_all(block)?;
+        }
+    }
+    f.set_len(data.len() as u64)
+}
+
+struct Walker {
+    manifest: Manifest,
+    files: Vec<(u16, PathBuf, String)>,
+    next_fileid: u16,
+    symlinks: SymlinkPolicy,
+    visited: HashSet<(u64, u64)>, // directories already entered, against symlink loops
+}
+
+impl Walker {
+    fn walk(&mut self, dir: &Path, prefix: &str) -> io::Result<()> {
+        let mut children: Vec<fs::DirEntry> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+        children.sort_by_key(|c| c.file_name());
+        for child in children {
+            let name = child.file_name().into_string().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "non utf-8 file name")
+            })?;
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            let mut m = fs::symlink_metadata(child.path())?;
+            if m.file_type().is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Preserve => {
+                        let target = fs::read_link(child.path())?;
+                        self.push(EntryKind::Symlink, &m, path, target.to_string_lossy().into_owned(), false);
+                        continue;
+                    },
+                    SymlinkPolicy::Follow => m = fs::metadata(child.path())?,
+                }
+            }
+
+            if m.is_dir() {
+                if !self.visited.insert((m.dev(), m.ino())) {
+                    continue;
+                }
+                self.push(EntryKind::Dir, &m, path.clone(), String::new(), false);
+                self.walk(&child.path(), &path)?;
+            } else if m.is_file() {
+                // the fileids from SOLICIT_FILEID up are reserved for discovery and probes
+                let fileid = self.next_fileid.checked_add(1).filter(|f| *f < general::SOLICIT_FILEID).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "too many files for the fileids left")
+                })?;
+                self.next_fileid = fileid;
+                // fewer allocated blocks than the length implies holes
+                let sparse = m.blocks() * 512 < m.len();
+                self.files.push((fileid, child.path(), path.clone()));
+                self.push(EntryKind::File, &m, path, String::new(), sparse);
+            }
+            // sockets, fifos and devices are not transferred
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, kind: EntryKind, m: &fs::Metadata, path: String, target: String, sparse: bool) {
+        self.manifest.entries.push(Entry {
+            kind: kind,
+            fileid: if kind == EntryKind::File { self.next_fileid } else { 0 },
+            sparse: sparse,
+            mode: m.mode() & 0o7777,
+            mtime: m.mtime(),
+            path: path,
+            target: target,
+        });
+    }
+}
+
+// whether `target`, resolved relative to the directory of `link`, stays inside the tree.
+// the link's directories and every step of the target but the last must not be links,
+// of the tree or already under `root`, as those could lead anywhere
+fn stays_inside(root: &Path, links: &HashSet<PathBuf>, link: &Path, target: &str) -> bool {
+    let is_link = |path: &Path| {
+        links.contains(path) || fs::symlink_metadata(root.join(path)).map_or(false, |m| m.file_type().is_symlink())
+    };
+    let mut resolved = link.parent().map_or(PathBuf::new(), Path::to_path_buf);
+    if resolved.ancestors().any(|a| !a.as_os_str().is_empty() && is_link(a)) {
+        return false;
+    }
+    let components: Vec<Component> = Path::new(target).components().collect();
+    for (i, component) in components.iter().enumerate() {
+        match component {
+            Component::Normal(c) => resolved.push(c),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return false;
+                }
+            },
+            _ => return false,
+        }
+        if i + 1 < components.len() && !resolved.as_os_str().is_empty() && is_link(&resolved) {
+            return false;
+        }
+    }
+    true
+}
+
+// `path` of a manifest entry without `.` components, relative to the root
+fn relative(path: &str) -> PathBuf {
+    Path::new(path).components().filter(|c| matches!(c, Component::Normal(_))).collect()
+}
+
+fn to_system_time(secs: i64) -> Option<time::SystemTime> {
+    if secs >= 0 {
+        time::UNIX_EPOCH.checked_add(time::Duration::from_secs(secs as u64))
+    } else {
+        time::UNIX_EPOCH.checked_sub(time::Duration::from_secs(secs.unsigned_abs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an empty directory of its own for each test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("robust-tree-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(kind: EntryKind, path: &str, target: &str) -> Entry {
+        Entry {
+            kind: kind,
+            fileid: 0,
+            sparse: false,
+            mode: 0o755,
+            mtime: 0,
+            path: String::from(path),
+            target: String::from(target),
+        }
+    }
+
+    fn manifest(entries: Vec<Entry>) -> Manifest {
+        Manifest { entries: entries }
+    }
+
+    #[test]
+    fn reserved_fileids() {
+        let root = scratch("reserved");
+        File::create(root.join("a")).unwrap();
+        let (_, files) = Manifest::walk(&root, general::SOLICIT_FILEID - 2, SymlinkPolicy::Skip).unwrap();
+        assert_eq!(files[0].0, general::SOLICIT_FILEID - 1);
+        File::create(root.join("b")).unwrap();
+        assert!(Manifest::walk(&root, general::SOLICIT_FILEID - 2, SymlinkPolicy::Skip).is_err());
+        fs::remove_file(root.join("a")).unwrap();
+        fs::remove_file(root.join("b")).unwrap();
+        assert!(Manifest::walk(&root, general::SOLICIT_FILEID, SymlinkPolicy::Skip).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn links_inside() {
+        let root = scratch("inside");
+        let m = manifest(vec![
+            entry(EntryKind::Dir, "d", ""),
+            entry(EntryKind::Symlink, "d/up", "../f"),
+            entry(EntryKind::Symlink, "here", "./d"),
+            entry(EntryKind::Symlink, "top", "."),
+        ]);
+        m.create_dirs(&root).unwrap();
+        m.create_symlinks(&root).unwrap();
+        assert_eq!(fs::read_link(root.join("d/up")).unwrap(), Path::new("../f"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn links_escaping() {
+        for (i, entries) in vec![
+            vec![entry(EntryKind::Symlink, "a", "../x")],
+            vec![entry(EntryKind::Symlink, "a", "/etc")],
+            // each link stays inside on its own, not through the other
+            vec![entry(EntryKind::Symlink, "a", "."), entry(EntryKind::Symlink, "a/b", "../x")],
+            vec![entry(EntryKind::Symlink, "a", "."), entry(EntryKind::Symlink, "b", "a/../x")],
+        ].into_iter().enumerate() {
+            let root = scratch(&format!("escaping{}", i));
+            let e = manifest(entries).create_symlinks(&root).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[test]
+    fn dir_modes() {
+        let root = scratch("modes");
+        let mut d = entry(EntryKind::Dir, "d", "");
+        d.mode = 0o3775;
+        let m = manifest(vec![d]);
+        m.create_dirs(&root).unwrap();
+        m.finish_dirs(&root).unwrap();
+        assert_eq!(fs::metadata(root.join("d")).unwrap().mode() & 0o7777, 0o775);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn existing_symlinks() {
+        let root = scratch("existing");
+        let outside = scratch("existing-outside");
+        unix_fs::symlink(&outside, root.join("out")).unwrap();
+        unix_fs::symlink(outside.join("f"), root.join("f")).unwrap();
+
+        let m = manifest(vec![entry(EntryKind::Dir, "out/d", "")]);
+        assert!(m.create_dirs(&root).is_err());
+        assert!(meta::create_path(&root, "out/g").is_err());
+        assert!(write_file(&meta::create_path(&root, "f").unwrap(), b"data", false).is_err());
+        let m = manifest(vec![entry(EntryKind::Symlink, "l", "out/../x")]);
+        assert!(m.create_symlinks(&root).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -4,6 +4,97 @@ pub const IP_HEADER_LENGTH: usize = 20;
 #[allow(dead_code)]
 pub const UDP_HEADER_LENGTH: usize = 8;
 
+// local experimental, override with BindOptions::ethertype
+pub const ETHERTYPE: u16 = 0xEF7;
+
 pub const EFT_HEADER_LENGTH: usize = 8;
 
-pub const MAX_OFFSET_LENGTH: usize = 200;
\ No newline at end of file
+pub const FEC_HEADER_LENGTH: usize = 10;
+
+// session, sequence and tag of encrypted packets
+pub const AEAD_OVERHEAD: usize = 32;
+
+// used when the interface does not report its mtu
+pub const DEFAULT_MTU: usize = 1500;
+
+pub const MAX_MTU: usize = 9000;
+
+// large enough for a tagged jumbo frame
+pub const FRAME_BUFFER_LENGTH: usize = 16384;
+
+// frames handed to the datalink in one build_and_send
+pub const MAX_BATCH: usize = 64;
+
+// reserved for path mtu probes
+pub const PROBE_FILEID: u16 = u16::MAX;
+
+// reserved for discovery, see eft/discovery.rs
+pub const ANNOUNCE_FILEID: u16 = u16::MAX - 1;
+pub const SOLICIT_FILEID: u16 = u16::MAX - 2;
+
+// the most fragments a file can be received in
+pub const MAX_OFFSET_LENGTH: usize = 200;
+
+// the defaults of eft::config::Tuning
+pub const MAX_FRAGMENTS: usize = 200;
+pub const RTO: u32 = 5; // milliseconds
+pub const BULK_RTO: u32 = 20; // milliseconds
+pub const SEND_BURST: usize = 300;
+pub const TIMER_BACKDATE: u64 = 5000; // milliseconds
+
+pub const MAX_FIN_RETRIES: usize = 10;
+
+// retransmissions without hearing from the receiver, override with SendOptions::max_retries
+pub const MAX_RETRIES: usize = 100;
+
+// keepalives a receiver sends unanswered before giving up
+pub const MAX_KEEPALIVES: usize = 3;
+
+// milliseconds
+pub const RECV_POLL_INTERVAL: u64 = 10;
+
+// milliseconds
+pub const NACK_INTERVAL: u64 = 20;
+
+// milliseconds
+pub const NACK_BACKOFF: u64 = 30;
+
+// milliseconds
+pub const NACK_AGGREGATION_WINDOW: u64 = 10;
+
+// longest the send loop sleeps waiting for tokens, so that acknowledgements are
+// still handled in time, milliseconds
+pub const MAX_PACING_WAIT: u64 = 10;
+
+// milliseconds
+pub const MULTICAST_PROBE_INTERVAL: u64 = 200;
+
+// milliseconds
+pub const PROBE_TIMEOUT: u64 = 100;
+
+pub const MAX_PROBE_RETRIES: usize = 3;
+
+// milliseconds
+pub const REQUEST_INTERVAL: u64 = 100;
+
+// Requests, Lists and Stats waiting for InterfacePeer::serve, later ones are dropped
+// until it catches up and clients retransmit them
+pub const MAX_PENDING_REQUESTS: usize = 256;
+
+// milliseconds
+pub const ANNOUNCE_INTERVAL: u64 = 5000;
+
+// milliseconds
+pub const KEEPALIVE_INTERVAL: u64 = 1000;
+
+// silence after which a sender out of retries fails, milliseconds
+pub const PEER_TIMEOUT: u64 = 10000;
+
+// fileids 0.. taken by the hot folder and the receiving daemon
+pub const DAEMON_FILEIDS: u16 = 64;
+
+// how long a dropped file stays unchanged before it is sent, milliseconds
+pub const SETTLE_INTERVAL: u64 = 1000;
+
+// between two progress reports of a transfer, milliseconds
+pub const PROGRESS_INTERVAL: u64 = 200;
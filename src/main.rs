@@ -1,25 +1,95 @@
 use std::{
     env,
-    fs::File,
-    io::{
-        BufWriter,
-        Write,
-    },
+    path::Path,
+    process,
+    sync::mpsc,
     thread,
+    time,
 };
 
-use pnet::util::MacAddr;
+use pnet::{
+    datalink,
+    util::MacAddr,
+};
 
 mod general;
 mod utils;
 mod eft;
+mod daemon;
+mod display;
 
 #[allow(unused_must_use)]
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 4 {
+    let (args, mut config) = configure(env::args().collect());
+    match args.get(1).map(|arg| &**arg) {
+        Some("interfaces") => {
+            for interface in datalink::interfaces() {
+                let mac = interface.mac.map_or(String::from("-"), |mac| mac.to_string());
+                println!("{}\t{}", interface.name, mac);
+            }
+            return;
+        },
+        Some("peers") => { // peers <interface> [timeout in milliseconds]
+            let interface = args.get(2).expect("args error");
+            let timeout = match args.get(3).map_or(Ok(1000), |timeout| timeout.parse::<u64>()) {
+                Ok(timeout) => timeout,
+                Err(e) => {
+                    eprintln!("invalid timeout {}: {}", args[3], e);
+                    process::exit(2);
+                },
+            };
+            let discovered = config.bind_options().and_then(|options| {
+                eft::Interface::discover(interface, &options, time::Duration::from_millis(timeout))
+            });
+            let peers = match discovered {
+                Ok(peers) => peers,
+                Err(e) => {
+                    eprintln!("discovery on {} failed: {}", interface, e);
+                    process::exit(1);
+                },
+            };
+            for peer in peers {
+                println!("{}\t{}\t{:?}\t{:#06x}", peer.mac, peer.name, peer.role, peer.capabilities);
+            }
+            return;
+        },
+        Some("watch") => { // watch <interface> <dir> [peer]
+            let interface_name = args.get(2).expect("args error");
+            let dir = args.get(3).expect("args error");
+            let mut interface = eft::Interface::builder().config(&config).interface(interface_name).bind_sendmode().unwrap();
+            let peer = resolve(interface_name, args.get(4), &config);
+            let mtu = interface.mtu();
+            let send_options = eft::SendOptions {
+                digest: true,
+                ..Default::default()
+            };
+            daemon::HotFolder::new(dir, peer, mtu, &send_options).run(&mut interface).unwrap();
+            return;
+        },
+        Some("inbox") => { // inbox <interface> <dir> <peer> [template [overwrite|skip|suffix]]
+            let interface_name = args.get(2).expect("args error");
+            let dir = args.get(3).expect("args error");
+            let interface = eft::Interface::builder().config(&config).interface(interface_name).bind_recvmode().unwrap();
+            let peer = resolve(interface_name, Some(args.get(4).expect("args error")), &config);
+            let template = args.get(5).map_or("{name}", |template| &**template);
+            let collision = match args.get(6).map(|collision| &**collision) {
+                Some("overwrite") => daemon::Collision::Overwrite,
+                Some("skip") => daemon::Collision::Skip,
+                Some("suffix") | None => daemon::Collision::Suffix,
+                _ => panic!("args error"),
+            };
+            daemon::Inbox::new(dir, peer, template, collision).run(&interface).unwrap();
+            return;
+        },
+        _ => (),
+    }
+    if args.len() < 4 || args.len() > 6 {
         panic!("args error");
     }
+    if args.len() >= 5 {
+        config.backend = args[4].clone();
+    }
+    let builder = eft::Interface::builder().config(&config).interface(&args[2]);
 
     let role: &str = &args[3];
     match role {
@@ -30,43 +100,89 @@ fn main() {
             //     let filepath: String = format!("./data/data{}", id);
             //     interface.send(id, MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff), filepath, args[1].parse::<usize>().unwrap()).unwrap();
             // }
-            let mut interface = eft::Interface::bind_sendmode(&args[2]).unwrap();
+            let mut interface = builder.bind_sendmode().unwrap();
+            let peer = resolve(&args[2], args.get(5), &config);
             let mut fileids: Vec<u16> = Vec::new();
             let mut filepaths: Vec<String> = Vec::new();
             for id in 0..1000 {
                 fileids.push(id);
                 filepaths.push(format!("./data/data{}", id));
             }
-            interface.send_files(fileids, MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff), filepaths, args[1].parse::<usize>().unwrap()).unwrap();
+            let mtu = if args[1] == "auto" {
+                interface.mtu()
+            } else {
+                args[1].parse::<usize>().unwrap()
+            };
+            let (progress_tx, progress_rx) = mpsc::channel();
+            interface.set_progress(Some(progress_tx));
+            let n = fileids.len();
+            thread::spawn(move || display::Display::new(progress_rx, n).run());
+            interface.send_files(fileids, peer, filepaths, mtu).unwrap();
             loop {}
         },
         "receiver" => {
-            let interface = eft::Interface::bind_recvmode(&args[2]).unwrap();
+            let interface = builder.bind_recvmode().unwrap();
+            let peer = resolve(&args[2], args.get(5), &config);
+            let (progress_tx, progress_rx) = mpsc::channel();
+            interface.set_progress(Some(progress_tx));
+            let display = thread::spawn(move || display::Display::new(progress_rx, 1000).run());
             let mut threads: Vec<thread::JoinHandle<_>> = Vec::new();
             for id in 0..1000 {
                 let mut interface = interface.clone();
                 threads.push(thread::spawn(move || {
-                    let filepath: String = format!("./data/data{}", id);
-                    let mut stream = interface.stream(id, MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff)).unwrap();
-                    let data = if let Ok(d) = stream.read() {
-                        d
-                    } else {
-                        return
-                    };
-                    let mut f = BufWriter::new(
-                        if let Ok(f) = File::create(&filepath) {
-                            f
-                        } else {
-                            return
-                        }
-                    );
-                    f.write(&data);
+                    let mut stream = interface.stream(id, peer).unwrap();
+                    if let Err(e) = stream.save("./data") {
+                        eprintln!("fileid {}: {}", id, e);
+                    }
                 }));
             }
             for thread in threads {
                 thread.join();
             }
+            display.join();
         },
         _ => panic!("args error"),
     }
 }
+
+// strips `--config <file>` and `--set key=value` from the arguments and loads the
+// configuration they describe, see eft/config.rs
+fn configure(args: Vec<String>) -> (Vec<String>, eft::config::Config) {
+    let mut path: Option<String> = None;
+    let mut overrides: Vec<String> = Vec::new();
+    let mut rest: Vec<String> = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--config" => path = Some(args.next().expect("args error")),
+            "--set" => overrides.push(args.next().expect("args error")),
+            _ => rest.push(arg),
+        }
+    }
+    let config = eft::config::Config::load(path.as_ref().map(Path::new), &overrides).unwrap_or_else(|e| {
+        panic!("config error: {}", e)
+    });
+    (rest, config)
+}
+
+// the peer argument, by MAC address, by its name in the configuration or by the name
+// it announces, everyone by default.
+// called once bound, so that two nodes resolving each other both announce meanwhile
+fn resolve(interface_name: &str, peer: Option<&String>, config: &eft::config::Config) -> MacAddr {
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff),
+    };
+    if let Ok(mac) = peer.parse::<MacAddr>() {
+        return mac;
+    }
+    if let Some(mac) = config.peer(peer) {
+        return mac;
+    }
+    eft::Interface::discover(interface_name, &config.bind_options().unwrap(), time::Duration::from_secs(1))
+        .unwrap()
+        .into_iter()
+        .find(|p| p.name == *peer)
+        .map(|p| p.mac)
+        .expect("peer not found")
+}
@@ -1,11 +1,4 @@
-use std::{
-    fs::File,
-    io::{
-        self,
-        Read,
-        BufReader,
-    },
-};
+use std::io;
 
 use crate::general;
 
@@ -53,6 +46,25 @@ impl Flags {
         Ok(((self.flags[access / 32] >> (access % 32)) & 0b1) == 0b1)
     }
 
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.flags.iter().fold(Vec::new(), |mut acc, f| {
+            acc.extend_from_slice(&f.to_be_bytes());
+            acc
+        })
+    }
+
+    // the length is not part of the encoding
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut flags = Self::new();
+        if bytes.len() != flags.flags.len() * 4 {
+            return Err(io::Error::new(io::ErrorKind::Other, "flags error"));
+        }
+        for (i, chunk) in bytes.chunks(4).enumerate() {
+            flags.flags[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Ok(flags)
+    }
+
     #[allow(dead_code)]
     pub fn isallset(&self) -> bool {
         if let Some(length) = self.length {
@@ -63,26 +75,69 @@ impl Flags {
     }
 }
 
-pub fn split_file(filepath: &str, size: usize) -> io::Result<Vec<Vec<u8>>> {
-    let mut data_fragments: Vec<Vec<u8>> = Vec::new();
-    let mut f = BufReader::new(File::open(filepath)?);
-    loop {
-        let mut data_fragment: Vec<u8> = vec![0; size - general::EFT_HEADER_LENGTH];
-        let data_length: usize = f.read(&mut data_fragment)?;
-        if data_length == 0 {
-            return Ok(data_fragments);
-        }
-        data_fragment.resize(data_length, 0);
-        data_fragments.push(data_fragment);
+pub fn split_data(data: &[u8], size: usize) -> Vec<Vec<u8>> {
+    if data.is_empty() { // an empty file is a single empty DataEnd
+        return vec![Vec::new()];
     }
+    data.chunks(size - general::EFT_HEADER_LENGTH).map(|f| f.to_vec()).collect()
 }
 
-pub fn rstrip_null(bytes: &mut Vec<u8>) {
-    while let Some(b) = bytes.last() {
-        if *b == 0 {
-            bytes.pop().unwrap();
-        } else {
-            break;
+// reads the fields of a payload front to back, failing with InvalidData and
+// `error` past its end
+pub struct Reader<'a> {
+    raw: &'a [u8],
+    pos: usize,
+    error: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(raw: &'a [u8], error: &'static str) -> Self {
+        Self {
+            raw: raw,
+            pos: 0,
+            error: error,
         }
     }
-}
\ No newline at end of file
+
+    pub fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.raw.len() - self.pos < length {
+            return Err(self.error());
+        }
+        self.pos += length;
+        Ok(&self.raw[self.pos - length..self.pos])
+    }
+
+    // a fixed size field, e.g. `u32::from_be_bytes(r.array()?)`
+    pub fn array<T: Default + AsMut<[u8]>>(&mut self) -> io::Result<T> {
+        let mut a = T::default();
+        let length = a.as_mut().len();
+        a.as_mut().copy_from_slice(self.take(length)?);
+        Ok(a)
+    }
+
+    pub fn string(&mut self, length: usize) -> io::Result<String> {
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| self.error())
+    }
+
+    pub fn error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_bounds() {
+        let mut r = Reader::new(&[0, 1, 2, 0xff, 0xfe], "test parse error");
+        assert_eq!(u16::from_be_bytes(r.array().unwrap()), 1);
+        assert_eq!(r.take(1).unwrap(), &[2]);
+        let e = r.take(usize::MAX).unwrap_err();
+        assert_eq!((e.kind(), e.to_string()), (io::ErrorKind::InvalidData, String::from("test parse error")));
+        assert!(r.array::<[u8; 3]>().is_err());
+        assert_eq!(r.string(2).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(r.take(1).is_err());
+        assert!(r.take(0).is_ok());
+    }
+}